@@ -1,7 +1,18 @@
-use std::{fs, path::PathBuf};
+use std::{
+    cell::Cell,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use anyhow::{Context, Result};
 use clap::Parser;
-use w4on2_shared::*;
+use w4on2_shared::{
+    bounce::{Bounce, BounceOptions, Normalize, SampleFormat},
+    crunch::CrunchControl,
+    *,
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -30,6 +41,36 @@ enum Args {
 
         #[arg(long, help = "don't crunch w4on2 file")]
         no_crunch: bool,
+
+        #[arg(long, help = "stop crunching after this many seconds and use the best result so far")]
+        crunch_seconds: Option<f64>,
+
+        #[arg(long, help = "stop crunching after finding this many patterns")]
+        crunch_patterns: Option<usize>,
+
+        #[arg(
+            long,
+            help = "try progressively lossier strategies until the output is at most this many bytes"
+        )]
+        max_bytes: Option<usize>,
+
+        #[arg(long, help = "print the conversion report as JSON")]
+        json: bool,
+    },
+    #[command(about = "Create a starter TOML for a MIDI file")]
+    Init {
+        #[arg(index = 1, help = "MIDI input file path")]
+        midi: PathBuf,
+
+        #[arg(
+            short = 'o',
+            long,
+            help = "TOML output file path - defaults to MIDI path with .toml extension"
+        )]
+        output: Option<PathBuf>,
+
+        #[arg(long, help = "overwrite the TOML file if it exists")]
+        force: bool,
     },
     #[command(about = "Convert a w4on2 file to WAV")]
     Bounce {
@@ -42,10 +83,135 @@ enum Args {
             help = "WAV output file path - defaults to input path with .wav extension"
         )]
         output: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Also write each WASM-4 channel to its own WAV file, named like the output with the channel added"
+        )]
+        stems: bool,
+
+        #[arg(long, requires = "stems", help = "Also write a stem for each w4on2 track")]
+        track_stems: bool,
+
+        #[command(flatten)]
+        options: BounceArgs,
+    },
+    #[command(about = "Convert a MIDI and TOML combo and bounce it to WAV without writing the w4on2 file")]
+    Render {
+        #[arg(index = 1, help = "Song definition file path")]
+        toml: PathBuf,
+
+        #[arg(
+            short = 'm',
+            long,
+            help = "MIDI input file path - defaults to TOML path with .mid extension"
+        )]
+        midi: Option<PathBuf>,
+
+        #[arg(
+            short = 'o',
+            long,
+            help = "WAV output file path - defaults to TOML path with .wav extension"
+        )]
+        output: Option<PathBuf>,
+
+        #[arg(long, help = "don't stretch to optimal bpm")]
+        no_stretch: bool,
+
+        #[arg(long, help = "don't crunch, which only makes the printed size larger")]
+        no_crunch: bool,
+
+        #[command(flatten)]
+        options: BounceArgs,
     },
 }
 
-fn main() {
+#[derive(clap::Args)]
+struct BounceArgs {
+    #[arg(
+        long,
+        default_value_t = bounce::WASM4_SAMPLE_RATE,
+        value_parser = clap::value_parser!(u32).range(bounce::MIN_SAMPLE_RATE as i64..=bounce::MAX_SAMPLE_RATE as i64),
+        help = "output sample rate in Hz, 8000 to 192000"
+    )]
+    sample_rate: u32,
+
+    #[arg(long, default_value_t = SampleFormat::Int16, help = "output sample format: 16, 24 or 32f (float)")]
+    format: SampleFormat,
+
+    #[arg(long, help = "dither when writing 16 or 24 bit samples")]
+    dither: bool,
+
+    #[arg(
+        long,
+        allow_negative_numbers = true,
+        help = "normalize so the highest peak is at this dBFS"
+    )]
+    normalize_peak: Option<f64>,
+
+    #[arg(
+        long,
+        conflicts_with = "normalize_peak",
+        allow_negative_numbers = true,
+        help = "normalize to this integrated loudness in LUFS"
+    )]
+    normalize_lufs: Option<f64>,
+
+    #[arg(long, default_value_t = 1, help = "times to play the song")]
+    loops: u32,
+
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "seconds to fade out at the end of the last loop"
+    )]
+    fade_out: f64,
+
+    #[arg(long, default_value_t = bounce::DEFAULT_PADDING, help = "seconds of silence before the song")]
+    lead_in: f64,
+
+    #[arg(long, default_value_t = bounce::DEFAULT_PADDING, help = "seconds to keep going after the song")]
+    tail: f64,
+
+    #[arg(long, help = "wait for every channel to finish its release before the tail")]
+    tail_release: bool,
+}
+impl BounceArgs {
+    fn options(&self) -> BounceOptions {
+        BounceOptions {
+            sample_rate: self.sample_rate,
+            format: self.format,
+            dither: self.dither,
+            normalize: self
+                .normalize_peak
+                .map(Normalize::Peak)
+                .or(self.normalize_lufs.map(Normalize::Loudness)),
+            loops: self.loops,
+            fade_out: self.fade_out,
+            lead_in: self.lead_in,
+            tail: self.tail,
+            until_released: self.tail_release,
+        }
+    }
+}
+
+fn print_bounce(mix: &Bounce) {
+    println!("{}", mix);
+    if mix.clipped.samples > 0 {
+        println!("Clipped: {}", mix.clipped);
+    }
+}
+
+fn load_song(toml: &Path, midi: Option<PathBuf>) -> Result<(SongConfig, Vec<u8>)> {
+    let midi_path = midi.unwrap_or(toml.with_extension("mid"));
+    let midi_bytes = fs::read(midi_path).context("failed to load midi file")?;
+    let toml_str = fs::read_to_string(toml).context("failed to load toml file")?;
+    let toml_dir = toml.parent().unwrap_or(Path::new(""));
+    let conf = SongConfig::from_toml_in(&toml_str, toml_dir).context("failed to parse toml")?;
+    Ok((conf, midi_bytes))
+}
+
+fn main() -> Result<()> {
     let args = Args::parse();
 
     match args {
@@ -55,22 +221,117 @@ fn main() {
             output,
             no_stretch,
             no_crunch,
+            crunch_seconds,
+            crunch_patterns,
+            max_bytes,
+            json,
         } => {
-            let midi_path = midi.unwrap_or(toml.with_extension("mid"));
             let output_path = output.unwrap_or(toml.with_extension("w4on2"));
-            let midi_bytes = fs::read(midi_path).expect("failed to load midi file");
-            let toml_str = fs::read_to_string(&toml).expect("failed to load toml file");
-            let conf = SongConfig::from_toml(&toml_str).expect("failed to parse toml");
-            let serialized =
-                w4on2_shared::convert::convert(&conf, &midi_bytes, !no_stretch, !no_crunch).expect("failed to convert");
-            fs::write(output_path, serialized).expect("failed to write file");
+            let (conf, midi_bytes) = load_song(&toml, midi)?;
+            let progress_shown = Cell::new(false);
+            let control = (!no_crunch).then(|| CrunchControl {
+                on_progress: Some(Box::new(|p| {
+                    progress_shown.set(true);
+                    eprint!(
+                        "\rCrunching: {:3.0}% | {} patterns | {} bytes saved",
+                        p.fraction * 100.0,
+                        p.patterns,
+                        p.saved
+                    );
+                    io::stderr().flush().ok();
+                })),
+                time_budget: crunch_seconds.map(Duration::from_secs_f64),
+                max_iterations: crunch_patterns,
+                ..Default::default()
+            });
+            let result = match max_bytes {
+                Some(max_bytes) => {
+                    w4on2_shared::convert::convert_to_fit(&conf, &midi_bytes, !no_stretch, control, max_bytes)
+                }
+                None => w4on2_shared::convert::convert_with(&conf, &midi_bytes, !no_stretch, control),
+            };
+            if progress_shown.get() {
+                eprintln!();
+            }
+            let (serialized, report) = result.context("failed to convert")?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+            fs::write(output_path, serialized).context("failed to write file")?;
+        }
+        Args::Init { midi, output, force } => {
+            let output_path = output.unwrap_or(midi.with_extension("toml"));
+            if output_path.exists() && !force {
+                anyhow::bail!("{} already exists, use --force to overwrite it", output_path.display());
+            }
+            let midi_bytes = fs::read(&midi).context("failed to load midi file")?;
+            let parts = w4on2_shared::starter::scan_midi(&midi_bytes).context("failed to scan midi file")?;
+            for p in &parts {
+                println!(
+                    "MIDI track {}{}, channel {}: {} notes, keys {}-{}, up to {} at once",
+                    p.midi_track,
+                    p.name.as_ref().map(|n| format!(" ({})", n)).unwrap_or_default(),
+                    p.channel,
+                    p.notes,
+                    p.lowest,
+                    p.highest,
+                    p.polyphony
+                );
+            }
+            let conf = w4on2_shared::starter::starter_config(&parts);
+            fs::write(&output_path, conf.to_toml()?).context("failed to write file")?;
+            println!("Wrote {}", output_path.display());
         }
-        Args::Bounce { input, output } => {
-            let w4on2_bytes = fs::read(&input).expect("failed to load midi file");
+        Args::Bounce {
+            input,
+            output,
+            stems,
+            track_stems,
+            options,
+        } => {
+            let w4on2_bytes = fs::read(&input).context("failed to load w4on2 file")?;
             let output_path = output.unwrap_or(input.with_extension("wav"));
-            let pcm = w4on2_shared::bounce::bounce_pcm(&w4on2_bytes);
-            let mut output_file = std::fs::File::create(output_path).expect("failed to open output file");
-            w4on2_shared::bounce::write_wav(pcm, &mut output_file).expect("failed to write output file");
+            let options = options.options();
+            let mix = bounce::bounce(&w4on2_bytes, &options)?;
+            print_bounce(&mix);
+            let mut output_file = std::fs::File::create(&output_path).context("failed to open output file")?;
+            bounce::write_wav(&mix, &options, &mut output_file).context("failed to write output file")?;
+            if stems {
+                for (name, stem) in bounce::bounce_stems(&w4on2_bytes, track_stems, &options)? {
+                    let stem_path = output_path.with_extension(format!("{}.wav", name));
+                    let mut stem_file = std::fs::File::create(&stem_path).context("failed to open stem file")?;
+                    bounce::write_wav(&stem, &options, &mut stem_file).context("failed to write stem file")?;
+                    println!("Wrote {}", stem_path.display());
+                }
+            }
+        }
+        Args::Render {
+            toml,
+            midi,
+            output,
+            no_stretch,
+            no_crunch,
+            options,
+        } => {
+            let output_path = output.unwrap_or(toml.with_extension("wav"));
+            let (conf, midi_bytes) = load_song(&toml, midi)?;
+            let options = RenderOptions {
+                stretch: !no_stretch,
+                crunch: !no_crunch,
+                bounce: options.options(),
+            };
+            let (mix, report) = w4on2_shared::render(&conf, &midi_bytes, &options).context("failed to convert")?;
+            println!(
+                "Song: {} bytes ({})",
+                report.size,
+                if report.crunched { "crunched" } else { "not crunched" }
+            );
+            print_bounce(&mix);
+            let mut output_file = std::fs::File::create(&output_path).context("failed to open output file")?;
+            bounce::write_wav(&mix, &options.bounce, &mut output_file).context("failed to write output file")?;
         }
     }
+    Ok(())
 }
@@ -10,12 +10,15 @@ use serde::{Deserialize, Serialize};
 use std::{
     ffi::c_void,
     ops::RangeInclusive,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex, RwLock},
 };
+use w4on2_shared::convert::{ConvertError, ConvertReport};
+use w4on2_shared::crunch::{CancelToken, CrunchControl, CrunchProgress};
 use w4on2_shared::SONG_TRACK_CONFIG_DEFAULT as STCD;
 use w4on2_shared::{optimal_bpm, runtime::*, Channel, PulseDuty, SongTrackConfig, TrackEvent};
-use w4on2_shared::{wasm4_apu, MidiEventMapper, SongConfig};
+use w4on2_shared::{wasm4_apu, MidiEventMapper, QuantizeConfig, QuantizeMode, SongConfig, Swing, W4PlayerSong};
+use w4on2_shared::{MIDI_CC_SLIDE, MIDI_CC_SLIDE_TICKS, MIDI_CC_TREMOLO_DEPTH, MIDI_CC_TREMOLO_SPEED};
 use widgets::Knob;
 
 mod widgets;
@@ -35,6 +38,7 @@ struct Generator {
     mapper: MidiEventMapper,
     event_buffer: Vec<TrackEvent>,
     serialize_buffer: Vec<u8>,
+    macro_buffer: Vec<u8>, // song without patterns or tracks, for the runtime to look up macros in
 }
 unsafe impl Send for Generator {}
 impl Generator {
@@ -54,19 +58,27 @@ impl Generator {
             mapper: MidiEventMapper::new(),
             event_buffer: Vec::with_capacity(16),
             serialize_buffer: Vec::with_capacity(16),
+            macro_buffer: vec![],
         }
     }
     fn reload_instruments(&mut self, conf: &SongConfig) {
         self.mapper.set_tracks(conf.channels.clone());
+        self.macro_buffer = W4PlayerSong {
+            patterns: vec![],
+            tracks: vec![],
+            macros: self.mapper.macros().to_vec(),
+        }
+        .serialize();
+        self.engine.data = self.macro_buffer.as_ptr();
     }
 }
 
 enum ConvertStatus {
     NoPath,
     Waiting(PathBuf),
-    Converting,
+    Converting(PathBuf, CrunchProgress, CancelToken),
     Failed(PathBuf),
-    Ok(PathBuf, Vec<u8>),
+    Ok(PathBuf, Vec<u8>, ConvertReport),
 }
 
 pub struct W4ON2 {
@@ -145,9 +157,39 @@ fn num_ctrl<Num: emath::Numeric>(
     });
 }
 
+fn quantize_ctrl_ui(ui: &mut egui::Ui, quantize: &mut QuantizeConfig) {
+    let mut changed = false; // nothing to reload, it's only used when converting
+    ui.horizontal(|ui| {
+        ui.label("Quantize");
+        egui::ComboBox::from_id_source("quantize_mode")
+            .selected_text(quantize.mode.to_string())
+            .show_ui(ui, |ui| {
+                for t in QuantizeMode::types() {
+                    ui.selectable_value(&mut quantize.mode, t, t.to_string());
+                }
+            });
+        num_ctrl(&mut changed, ui, "Min len", &mut quantize.min_note_ticks, 0..=32, 1);
+        let mut swing = quantize.swing.is_some();
+        if ui
+            .checkbox(&mut swing, "Swing")
+            .on_hover_text("Delay every second part of a beat")
+            .changed()
+        {
+            quantize.swing = swing.then_some(Swing {
+                divisions: 2,
+                amount: 50.0,
+            });
+        }
+        if let Some(swing) = &mut quantize.swing {
+            num_ctrl(&mut changed, ui, "Div", &mut swing.divisions, 1..=16, 2);
+            num_ctrl(&mut changed, ui, "Amount", &mut swing.amount, 0.0..=100.0, 50.0);
+        }
+    });
+}
+
 fn load_toml_path(toml_path: &PathBuf) -> Option<SongConfig> {
     if let Ok(toml) = std::fs::read_to_string(toml_path) {
-        match SongConfig::from_toml(&toml) {
+        match SongConfig::from_toml_in(&toml, toml_path.parent().unwrap_or(Path::new(""))) {
             Ok(conf) => {
                 return Some(conf);
             }
@@ -172,15 +214,27 @@ fn run_convert_midi(
     midi_path: PathBuf,
 ) {
     std::thread::spawn(move || {
-        *status.lock().unwrap() = ConvertStatus::Converting;
+        let cancel = CancelToken::default();
+        *status.lock().unwrap() =
+            ConvertStatus::Converting(midi_path.clone(), CrunchProgress::default(), cancel.clone());
         if let Ok(midi_bytes) = std::fs::read(&midi_path) {
-            match w4on2_shared::convert::convert(&song_conf, &midi_bytes, conv_conf.stretch, conv_conf.crunch) {
-                Ok(converted) => {
-                    *status.lock().unwrap() = ConvertStatus::Ok(midi_path, converted);
+            let control = conv_conf.crunch.then(|| CrunchControl {
+                on_progress: Some(Box::new(|p: &CrunchProgress| {
+                    *status.lock().unwrap() = ConvertStatus::Converting(midi_path.clone(), p.clone(), cancel.clone());
+                })),
+                cancel: Some(cancel.clone()),
+                ..Default::default()
+            });
+            match w4on2_shared::convert::convert_with(&song_conf, &midi_bytes, conv_conf.stretch, control) {
+                Ok((converted, report)) => {
+                    *status.lock().unwrap() = ConvertStatus::Ok(midi_path, converted, report);
+                }
+                Err(ConvertError::Cancelled) => {
+                    *status.lock().unwrap() = ConvertStatus::Waiting(midi_path);
                 }
                 Err(err) => {
                     *status.lock().unwrap() = ConvertStatus::Failed(midi_path);
-                    simple_error(format!("Failed to parse file: {}", err))
+                    simple_error(format!("Failed to convert MIDI file:\n{}", err))
                 }
             }
         } else {
@@ -195,8 +249,32 @@ fn save_w4on2(w4on2_path: &PathBuf, data: &[u8]) {
     }
 }
 
-fn channel_ctrl_ui(ui: &mut egui::Ui, ch: &mut SongTrackConfig) -> bool {
-    let mut changed = Frame::group(ui.style())
+fn channel_ctrl_ui(ui: &mut egui::Ui, ch: &mut SongTrackConfig, presets: &[(String, SongTrackConfig)]) -> bool {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        ui.label("Preset");
+        egui::ComboBox::from_id_source("preset")
+            .selected_text(ch.preset.as_deref().unwrap_or("None"))
+            .height(400.0) // show all
+            .show_ui(ui, |ui| {
+                for (name, preset) in presets {
+                    if ui.selectable_label(ch.preset.as_ref() == Some(name), name).clicked() {
+                        ch.apply_preset(name, preset);
+                        changed = true;
+                    }
+                }
+            });
+        if ch.preset.is_some()
+            && ui
+                .button("Detach")
+                .on_hover_text("Keep the settings without the preset")
+                .clicked()
+        {
+            ch.preset = None;
+            changed = true;
+        }
+    });
+    changed |= Frame::group(ui.style())
         .show(ui, |ui| {
             ui.horizontal(|ui| {
                 ui.label("WASM-4 Channel");
@@ -248,6 +326,15 @@ fn channel_ctrl_ui(ui: &mut egui::Ui, ch: &mut SongTrackConfig) -> bool {
         );
         num_ctrl(&mut changed, ui, "R", &mut ch.adsr.3, 0..=255, STCD.adsr.3);
         num_ctrl(&mut changed, ui, "Porta", &mut ch.portamento, 0..=255, STCD.portamento);
+        num_ctrl(&mut changed, ui, "Glide", &mut ch.glide, 0..=255, STCD.glide);
+        changed |= ui
+            .checkbox(&mut ch.release_effects, "Release FX")
+            .on_hover_text("Keep pitch effects and arpeggio running during Release")
+            .changed();
+        changed |= ui
+            .checkbox(&mut ch.match_nickname, "Match track")
+            .on_hover_text("When converting, take notes from the MIDI track named like this channel's nickname")
+            .changed();
     });
     ui.horizontal(|ui| {
         Frame::group(ui.style()).show(ui, |ui| {
@@ -312,6 +399,70 @@ fn channel_ctrl_ui(ui: &mut egui::Ui, ch: &mut SongTrackConfig) -> bool {
             });
         });
     });
+    ui.horizontal(|ui| {
+        Frame::group(ui.style()).show(ui, |ui| {
+            ui.vertical(|ui| {
+                ui.label("Tremolo");
+                ui.horizontal(|ui| {
+                    num_ctrl(
+                        &mut changed,
+                        ui,
+                        "Freq",
+                        &mut ch.tremolo.speed,
+                        0..=255,
+                        STCD.tremolo.speed,
+                    );
+                    num_ctrl(
+                        &mut changed,
+                        ui,
+                        "Amount",
+                        &mut ch.tremolo.depth,
+                        0..=W4ON2_TREMOLO_DEPTH_MAX as u8,
+                        STCD.tremolo.depth,
+                    );
+                });
+            });
+        });
+        if let Channel::Pulse1(_) | Channel::Pulse2(_) = ch.channel {
+            Frame::group(ui.style()).show(ui, |ui| {
+                ui.vertical(|ui| {
+                    ui.label("Duty Sequence");
+                    ui.horizontal(|ui| {
+                        num_ctrl(
+                            &mut changed,
+                            ui,
+                            "Ticks",
+                            &mut ch.duty_sequence.rate,
+                            0..=W4ON2_DUTY_SEQ_RATE_MAX as u8,
+                            STCD.duty_sequence.rate,
+                        );
+                        let mut step_count = ch.duty_sequence.steps.len() as u8;
+                        num_ctrl(
+                            &mut changed,
+                            ui,
+                            "Steps",
+                            &mut step_count,
+                            0..=W4ON2_DUTY_SEQ_MAX_STEPS as u8,
+                            STCD.duty_sequence.steps.len() as u8,
+                        );
+                        ch.duty_sequence.steps.resize(step_count as usize, PulseDuty::D12_5);
+                        for (step_i, dc) in ch.duty_sequence.steps.iter_mut().enumerate() {
+                            changed |= egui::ComboBox::from_id_source(("duty_step", step_i))
+                                .width(60.0)
+                                .selected_text(dc.to_string())
+                                .show_ui(ui, |ui| {
+                                    PulseDuty::types()
+                                        .iter()
+                                        .fold(false, |a, t| ui.selectable_value(dc, *t, t.to_string()).clicked() || a)
+                                })
+                                .inner
+                                .unwrap_or(false);
+                        }
+                    });
+                });
+            });
+        }
+    });
     changed
 }
 
@@ -463,7 +614,8 @@ impl Plugin for W4ON2 {
                                     //  TODO: eframe?
                                     //}
                                 });
-                                if channel_ctrl_ui(ui, &mut song_conf.channels[*selected_channel]) {
+                                let presets = song_conf.preset_library();
+                                if channel_ctrl_ui(ui, &mut song_conf.channels[*selected_channel], &presets) {
                                     gen.lock().unwrap().as_mut().unwrap().reload_instruments(song_conf);
                                 }
                                 // TODO: show channel sound bars to the right :]
@@ -473,7 +625,7 @@ impl Plugin for W4ON2 {
                                 let conv_conf = &mut params.convert_config.write().unwrap();
                                 let status = &*convert_status.lock().unwrap();
 
-                                ui.add_enabled_ui(!matches!(status, ConvertStatus::Converting), |ui| {
+                                ui.add_enabled_ui(!matches!(status, ConvertStatus::Converting(..)), |ui| {
                                     if ui.button("Load MIDI...").clicked() {
                                         let conv_t = convert_status.clone();
                                         std::thread::spawn(move || {
@@ -488,16 +640,17 @@ impl Plugin for W4ON2 {
                                     }
                                     ui.horizontal(|ui| {
                                         ui.checkbox(&mut conv_conf.stretch, "Stretch to optimal BPM");
-                                        ui.checkbox(&mut conv_conf.crunch, "Crunch/compress file (slow)");
+                                        ui.checkbox(&mut conv_conf.crunch, "Crunch/compress file");
                                     });
+                                    quantize_ctrl_ui(ui, &mut song_conf.quantize);
                                 });
                                 ui.horizontal(|ui| {
                                     let conv_path = match status {
                                         ConvertStatus::NoPath => None,
-                                        ConvertStatus::Converting => None,
+                                        ConvertStatus::Converting(..) => None,
                                         ConvertStatus::Waiting(p) => Some(p),
                                         ConvertStatus::Failed(p) => Some(p),
-                                        ConvertStatus::Ok(p, _) => Some(p),
+                                        ConvertStatus::Ok(p, ..) => Some(p),
                                     };
                                     ui.add_enabled_ui(conv_path.is_some(), |ui| {
                                         if ui.button("Run").clicked() {
@@ -516,18 +669,25 @@ impl Plugin for W4ON2 {
                                         ConvertStatus::Waiting(_) => {
                                             ui.label("Ready");
                                         }
-                                        ConvertStatus::Converting => {
-                                            ui.label("Converting...");
+                                        ConvertStatus::Converting(_, progress, cancel) => {
+                                            if ui.button("Cancel").clicked() {
+                                                cancel.cancel();
+                                            }
+                                            ui.add(egui::ProgressBar::new(progress.fraction).text(format!(
+                                                "Converting... {} patterns, {} bytes saved",
+                                                progress.patterns, progress.saved
+                                            )));
+                                            ui.ctx().request_repaint();
                                         }
                                         ConvertStatus::Failed(_) => {
                                             ui.label("Failed!");
                                         }
-                                        ConvertStatus::Ok(_, c) => {
+                                        ConvertStatus::Ok(_, c, _) => {
                                             ui.label(format!("Converted! {} bytes.", c.len()));
                                         }
                                     }
                                 });
-                                ui.add_enabled_ui(matches!(status, ConvertStatus::Ok(_, _)), |ui| {
+                                ui.add_enabled_ui(matches!(status, ConvertStatus::Ok(..)), |ui| {
                                     if ui.button("Save w4on2...").clicked() {
                                         let conv_t = convert_status.clone();
                                         std::thread::spawn(move || {
@@ -536,13 +696,19 @@ impl Plugin for W4ON2 {
                                                 .set_directory("/")
                                                 .save_file();
                                             if let Some(f) = file {
-                                                if let ConvertStatus::Ok(_, data) = &*conv_t.lock().unwrap() {
+                                                if let ConvertStatus::Ok(_, data, _) = &*conv_t.lock().unwrap() {
                                                     save_w4on2(&f, data);
                                                 }
                                             }
                                         });
                                     }
                                 });
+                                if let ConvertStatus::Ok(_, _, report) = status {
+                                    ui.separator();
+                                    egui::ScrollArea::vertical().show(ui, |ui| {
+                                        ui.monospace(report.to_string());
+                                    });
+                                }
                             }
                         }
                     });
@@ -609,6 +775,16 @@ impl Plugin for W4ON2 {
                     NoteEvent::MidiCC { channel, cc, value, .. } => {
                         if cc == control_change::PAN_MSB {
                             gen.mapper.pan(channel, (value * 127.0) as u8);
+                        } else if cc == MIDI_CC_TREMOLO_DEPTH {
+                            gen.mapper
+                                .tremolo_depth(&mut gen.event_buffer, channel, (value * 127.0) as u8);
+                        } else if cc == MIDI_CC_TREMOLO_SPEED {
+                            gen.mapper
+                                .tremolo_speed(&mut gen.event_buffer, channel, (value * 127.0) as u8);
+                        } else if cc == MIDI_CC_SLIDE {
+                            gen.mapper.slide(&mut gen.event_buffer, channel, (value * 127.0) as u8);
+                        } else if cc == MIDI_CC_SLIDE_TICKS {
+                            gen.mapper.slide_ticks(channel, (value * 127.0) as u8);
                         }
                     }
                     _ => {}
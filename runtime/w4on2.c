@@ -35,10 +35,28 @@ static int32_t w4on2_triangle(uint32_t phase, int32_t peak)
     }
 }
 
+static uint16_t w4on2_u16be(const uint8_t *data)
+{
+    return (uint16_t)(data[0] << 8) | (uint16_t)data[1];
+}
+
+// WASM-4 `tone` flags for the track, with the pulse duty rewritten if a duty sequence is active
+static uint32_t w4on2_tone_flags(const w4on2_track_t *track, uint16_t ticks)
+{
+    uint32_t flags = (track->flags & W4ON2_FLAG_WASM4_MASK) | 0x40;
+    uint8_t rate = track->duty_rate & W4ON2_DUTY_SEQ_RATE_MAX;
+    if (rate > 0) {
+        uint8_t step = (ticks / rate) % ((track->duty_rate >> 6) + 1);
+        flags = (flags & ~0x0c) | (((track->duty_steps >> (step * 2)) & 0x3) << 2);
+    }
+    return flags;
+}
+
 void w4on2_rt_init(w4on2_rt_t *rt, w4on2_tone_t tone, void *userdata)
 {
     rt->tone = tone;
     rt->userdata = userdata;
+    rt->data = 0;
     for (uint8_t i = 0; i < W4ON2_TRACK_COUNT; i++) {
         rt->tracks[i] = (w4on2_track_t){
             .velocity = W4ON2_VELOCITY_MAX,
@@ -52,20 +70,130 @@ void w4on2_rt_init(w4on2_rt_t *rt, w4on2_tone_t tone, void *userdata)
             .pe_duration = 0,
             .arp_rate = 0,
             .portamento = 0,
+            .glide = 0,
             .vib_speed = 0,
             .vib_depth = 0,
+            .trem_speed = 0,
+            .trem_depth = 0,
+            .duty_steps = 0,
+            .duty_rate = 0,
+            .volume_offset = 0,
         };
+        for (uint8_t j = 0; j < W4ON2_MACRO_KIND_COUNT; j++) {
+            rt->tracks[i].macros[j] = W4ON2_MACRO_NONE;
+        }
     }
     for (uint8_t i = 0; i < W4ON2_CHANNEL_COUNT; i++) {
         rt->channels[i] = (w4on2_channel_t){
             .active_track_i = 0xff,
             .active_key_count = 0,
+            .released_key_count = 0,
+            .release_ticks = 0,
             .first_trigger_ticks = 0,
             .last_trigger_ticks = 0,
+            .glide_key = W4ON2_NO_KEY,
+            .slide_semitones = 0,
+            .slide_duration = 0,
+            .slide_ticks = 0,
         };
     }
 }
 
+// Value of a track macro `ticks` into the note, or into the release if `released`
+static uint8_t w4on2_macro(const w4on2_rt_t *rt, const w4on2_track_t *track, uint8_t kind, uint16_t ticks, uint8_t released, uint8_t def)
+{
+    uint8_t macro_i = track->macros[kind];
+    if (macro_i == W4ON2_MACRO_NONE || !rt->data) {
+        return def;
+    }
+    const uint8_t *m = rt->data + w4on2_u16be(rt->data + W4ON2_HEADER_SIZE + (rt->data[2] + rt->data[3] + macro_i) * 2);
+    uint8_t len = m[0], loop = m[1], rel = m[2];
+
+    // Held notes play up until the release point, released notes play from it
+    uint32_t from = 0, end = len, pos = ticks;
+    if (rel < len) {
+        if (released) {
+            from = rel;
+            pos += rel;
+        } else {
+            end = rel;
+        }
+    } else if (released) {
+        pos += len; // no release point: keep looping or hold the last value
+    }
+    if (pos >= end) {
+        pos = loop >= from && loop < end
+            ? loop + (pos - loop) % (end - loop)
+            : end - 1;
+    }
+    return m[3 + pos];
+}
+
+static uint8_t w4on2_has_macros(const w4on2_track_t *track)
+{
+    for (uint8_t i = 0; i < W4ON2_MACRO_KIND_COUNT; i++) {
+        if (track->macros[i] != W4ON2_MACRO_NONE) {
+            return 1;
+        }
+    }
+    return 0;
+}
+
+// Apply macros and play one tick of a linear tone
+static void w4on2_tick_tone(w4on2_rt_t *rt, w4on2_channel_t *ch, w4on2_track_t *track, int32_t from_pitch, int32_t to_pitch, int32_t from_vol, int32_t to_vol)
+{
+    uint8_t released = ch->active_key_count == 0;
+    uint16_t macro_ticks = released ? ch->release_ticks : ch->first_trigger_ticks;
+    uint32_t flags = w4on2_tone_flags(track, ch->first_trigger_ticks);
+
+    // Macros
+    uint8_t macro_vol = w4on2_macro(rt, track, W4ON2_MACRO_VOLUME, macro_ticks, released, W4ON2_MACRO_VOLUME_MAX);
+    from_vol = (from_vol * macro_vol) / W4ON2_MACRO_VOLUME_MAX;
+    to_vol = (to_vol * macro_vol) / W4ON2_MACRO_VOLUME_MAX;
+    int32_t macro_pitch = ((int8_t)w4on2_macro(rt, track, W4ON2_MACRO_ARPEGGIO, macro_ticks, released, 0) << 8)
+        + ((int8_t)w4on2_macro(rt, track, W4ON2_MACRO_PITCH, macro_ticks, released, 0) << 4);
+    from_pitch += macro_pitch;
+    to_pitch += macro_pitch;
+    if (track->macros[W4ON2_MACRO_DUTY] != W4ON2_MACRO_NONE) {
+        flags = (flags & ~0x0c) | ((w4on2_macro(rt, track, W4ON2_MACRO_DUTY, macro_ticks, released, 0) & 0x3) << 2);
+    }
+
+    // Tremolo - starts at full volume and dips by up to `trem_depth`
+    if (track->trem_depth > 0) {
+        uint32_t trem_step = (uint32_t)track->trem_speed << 6;
+        from_vol -= (from_vol * (w4on2_triangle(((uint32_t)ch->first_trigger_ticks * trem_step) & 0xffff, track->trem_depth) + track->trem_depth))
+            / (2 * W4ON2_TREMOLO_DEPTH_MAX);
+        to_vol -= (to_vol * (w4on2_triangle(((uint32_t)(ch->first_trigger_ticks + 1) * trem_step) & 0xffff, track->trem_depth) + track->trem_depth))
+            / (2 * W4ON2_TREMOLO_DEPTH_MAX);
+    }
+
+    // Convert from pitch to WASM-4 bent MIDI notes to WASM-4 frequency slope
+    uint32_t w4_freq_param =
+        ((((uint32_t)from_pitch >> 8) | ((uint32_t)from_pitch << 8)) & 0xffff)
+        | (((((uint32_t)to_pitch >> 8) | ((uint32_t)to_pitch << 8)) & 0xffff) << 16);
+
+    // Continous linear tone
+    // Using the Decay part of ADSR is most flexible for playing any linear envelope since peak and sustain are absolute values in WASM-4.
+    // The downside is WASM-4 defaults peak volume to 100 when it is 0, so we use Attack specifically for that case (since it goes from zero.)
+    if (from_vol != 0) {
+        rt->tone(
+            w4_freq_param,
+            1 << 16, // decay
+            to_vol | (from_vol << 8),
+            flags,
+            rt->userdata
+        );
+    } else if (to_vol != 0) {
+        rt->tone(
+            w4_freq_param,
+            1 << 24, // attack
+            to_vol | (to_vol << 8), // both required
+            flags,
+            rt->userdata
+        );
+    }
+}
+
 void w4on2_rt_tick(w4on2_rt_t *rt)
 {
     // Play each channel
@@ -77,29 +205,61 @@ void w4on2_rt_tick(w4on2_rt_t *rt)
         w4on2_track_t *track = &rt->tracks[ch->active_track_i];
 
         // Convert volumes to WASM-4 values
-        uint32_t vel_undiv = (uint32_t)track->volume * (uint32_t)track->velocity;
+        int16_t volume = (int16_t)track->volume + track->volume_offset;
+        volume = volume < 0 ? 0 : volume > W4ON2_VOLUME_MAX ? W4ON2_VOLUME_MAX : volume;
+        uint32_t vel_undiv = (uint32_t)volume * (uint32_t)track->velocity;
         uint8_t peak_amp = (W4ON2_WASM4_VOLUME_MAX * vel_undiv) / (W4ON2_VOLUME_MAX * W4ON2_VELOCITY_MAX);
         uint8_t sus_amp = (W4ON2_WASM4_VOLUME_MAX * vel_undiv * (uint32_t)track->s) / (W4ON2_VOLUME_MAX * W4ON2_VELOCITY_MAX * W4ON2_SUSTAIN_MAX);
 
         // Handle note
-        if (ch->active_key_count > 0) {
+        // Released notes keep their keys so arpeggios and pitch effects can continue if wanted
+        uint8_t released = ch->active_key_count == 0;
+        uint8_t key_count = released ? ch->released_key_count : ch->active_key_count;
+        uint8_t tick_release = (track->flags & W4ON2_FLAG_RELEASE_EFFECTS) || w4on2_has_macros(track);
+        if (key_count == 0 || (released && tick_release && ch->release_ticks >= track->r)) {
+            // nothing playing, or Release has finished
+        } else if (released && !tick_release) {
+            // For Release we only trigger once and let WASM-4 handle the ramping
+            if (ch->release_ticks == 0) {
+                uint8_t key = track->arp_rate > 0
+                    ? ch->note_keys[(ch->first_trigger_ticks / track->arp_rate) % key_count]
+                    : ch->note_keys[key_count - 1];
+                rt->tone(
+                    key,
+                    track->r << 8,
+                    sus_amp,
+                    w4on2_tone_flags(track, ch->first_trigger_ticks),
+                    rt->userdata
+                );
+            }
+        } else {
             // Find current and last key
             // - notes: last in `ch->note_keys`
             // - arps: based on arp_rate
             uint8_t key_i = track->arp_rate > 0
-                ? (ch->first_trigger_ticks / track->arp_rate) % ch->active_key_count
-                : ch->active_key_count - 1;
+                ? (ch->first_trigger_ticks / track->arp_rate) % key_count
+                : key_count - 1;
             uint8_t key = ch->note_keys[key_i];
-            uint8_t prev_key = ch->note_keys[(key_i + ch->active_key_count - 1) % ch->active_key_count];
+            uint8_t prev_key = ch->note_keys[(key_i + key_count - 1) % key_count];
+            uint8_t portamento = track->portamento;
+
+            // Glide - like portamento, but from the previous note even if it was released
+            if (!released && key_count == 1 && track->glide > 0 && ch->glide_key != W4ON2_NO_KEY) {
+                prev_key = ch->glide_key;
+                portamento = track->glide;
+            }
 
             // ADS(R)
             // - notes: reset at the first note
             // - arps: reset with each arpeggio note
-            uint16_t key_ticks = track->arp_rate > 0 && ch->active_key_count >= 2
+            // - release: ramped per tick from sustain, since WASM-4 can't keep changing the pitch by itself
+            uint16_t key_ticks = track->arp_rate > 0 && key_count >= 2
                 ? ch->first_trigger_ticks % track->arp_rate
                 : ch->first_trigger_ticks;
             int32_t from_vol = 0, to_vol = 0;
-            if (key_ticks < track->a) { // attack
+            if (released) {
+                w4on2_ramp2add(&from_vol, &to_vol, ch->release_ticks, track->r, sus_amp, 0);
+            } else if (key_ticks < track->a) { // attack
                 w4on2_ramp2add(&from_vol, &to_vol, key_ticks, track->a, 0, peak_amp);
             } else { // decay & sustain
                 w4on2_ramp2add(&from_vol, &to_vol, key_ticks - track->a, track->d, peak_amp, sus_amp);
@@ -114,7 +274,10 @@ void w4on2_rt_tick(w4on2_rt_t *rt)
             uint16_t porta_ticks = track->arp_rate > 0
                 ? key_ticks
                 : ch->last_trigger_ticks;
-            w4on2_ramp2add(&from_pitch, &to_pitch, porta_ticks, track->portamento, prev_key << 8, key << 8);
+            w4on2_ramp2add(&from_pitch, &to_pitch, porta_ticks, portamento, prev_key << 8, key << 8);
+
+            // Slide
+            w4on2_ramp2add(&from_pitch, &to_pitch, ch->slide_ticks, ch->slide_duration, 0, ch->slide_semitones << 8);
 
             // Pitch envelope
             w4on2_ramp2add(&from_pitch, &to_pitch, key_ticks, track->pe_duration, track->pe_offset << 8, 0);
@@ -123,60 +286,25 @@ void w4on2_rt_tick(w4on2_rt_t *rt)
             from_pitch += w4on2_triangle((0x3fff + (uint32_t)porta_ticks * ((uint32_t)track->vib_speed << 6)) & 0xffff, track->vib_depth << 2);
             to_pitch += w4on2_triangle((0x3fff + (uint32_t)(porta_ticks + 1) * ((uint32_t)track->vib_speed << 6)) % 0xffff, track->vib_depth << 2);
 
-            // Convert from pitch to WASM-4 bent MIDI notes to WASM-4 frequency slope
-            uint32_t w4_freq_param =
-                ((((uint32_t)from_pitch >> 8) | ((uint32_t)from_pitch << 8)) & 0xffff)
-                | (((((uint32_t)to_pitch >> 8) | ((uint32_t)to_pitch << 8)) & 0xffff) << 16);
-
-            // Continous linear tone
-            // Using the Decay part of ADSR is most flexible for playing any linear envelope since peak and sustain are absolute values in WASM-4.
-            // The downside is WASM-4 defaults peak volume to 100 when it is 0, so we use Attack specifically for that case (since it goes from zero.)
-            if (from_vol != 0) {
-                rt->tone(
-                    w4_freq_param,
-                    1 << 16, // decay
-                    to_vol | (from_vol << 8),
-                    track->flags | 0x40,
-                    rt->userdata
-                );
-            } else if (to_vol != 0) {
-                rt->tone(
-                    w4_freq_param,
-                    1 << 24, // attack
-                    to_vol | (to_vol << 8), // both required
-                    track->flags | 0x40,
-                    rt->userdata
-                );
-            }
-        } else {
-            // For Release we only trigger once and let WASM-4 handle the ramping
-            if (ch->first_trigger_ticks == 0) {
-                uint8_t key = ch->note_keys[0]; // last released note is placed into ch->note_keys[0]
-                rt->tone(
-                    key,
-                    track->r << 8,
-                    sus_amp,
-                    track->flags | 0x40,
-                    rt->userdata
-                );
-            }
+            w4on2_tick_tone(rt, ch, track, from_pitch, to_pitch, from_vol, to_vol);
         }
 
         // Tick tock - avoid wrapping
         if (ch->first_trigger_ticks < 0xffff) {
             ch->first_trigger_ticks++;
         }
+        if (released && ch->release_ticks < 0xffff) {
+            ch->release_ticks++;
+        }
         if (ch->last_trigger_ticks < 0xff) {
             ch->last_trigger_ticks++;
         }
+        if (ch->slide_ticks < 0xff) {
+            ch->slide_ticks++;
+        }
     }
 }
 
-static uint16_t w4on2_u16be(const uint8_t *data)
-{
-    return (uint16_t)(data[0] << 8) | (uint16_t)data[1];
-}
-
 uint8_t w4on2_rt_feed_event(w4on2_rt_t *rt, uint8_t track_i, const uint8_t *data)
 {
     w4on2_track_t *t = &rt->tracks[track_i];
@@ -202,6 +330,7 @@ uint8_t w4on2_rt_feed_event(w4on2_rt_t *rt, uint8_t track_i, const uint8_t *data
         if (track_i != ch->active_track_i) {
             ch->active_track_i = track_i;
             ch->active_key_count = 0;
+            ch->glide_key = W4ON2_NO_KEY;
         }
         // note overflow: push notes downwards to leave room (pop first)
         if (ch->active_key_count >= W4ON2_MAX_NOTES) {
@@ -213,6 +342,7 @@ uint8_t w4on2_rt_feed_event(w4on2_rt_t *rt, uint8_t track_i, const uint8_t *data
         // new note
         if (ch->active_key_count == 0) {
             ch->first_trigger_ticks = 0;
+            ch->slide_semitones = 0;
         }
         // add
         ch->note_keys[ch->active_key_count++] = cmd - W4ON2_FMT_NOTE_ON_4_START;
@@ -220,13 +350,13 @@ uint8_t w4on2_rt_feed_event(w4on2_rt_t *rt, uint8_t track_i, const uint8_t *data
         return W4ON2_FMT_NOTE_ON_SIZE;
     } else if (cmd == W4ON2_FMT_NOTES_OFF_ID) {
         if (ch->active_key_count > 0) {
-            // last released note is place into ch->note_keys[0] with ch->first_trigger_ticks = 0
-            uint8_t key = t->arp_rate > 0
+            // keys stay in ch->note_keys, with ch->release_ticks counting from 0
+            ch->glide_key = t->arp_rate > 0
                 ? ch->note_keys[(ch->first_trigger_ticks / t->arp_rate) % ch->active_key_count]
                 : ch->note_keys[ch->active_key_count - 1];
-            ch->note_keys[0] = key;
+            ch->released_key_count = ch->active_key_count;
             ch->active_key_count = 0;
-            ch->first_trigger_ticks = 0;
+            ch->release_ticks = 0;
         }
         return W4ON2_FMT_NOTES_OFF_SIZE;
     } else if (cmd == W4ON2_FMT_SET_FLAGS_ARG1_ID) {
@@ -273,6 +403,27 @@ uint8_t w4on2_rt_feed_event(w4on2_rt_t *rt, uint8_t track_i, const uint8_t *data
         t->vib_speed = data[1];
         t->vib_depth = data[2];
         return W4ON2_FMT_SET_VIBRATO_SIZE;
+    } else if (cmd == W4ON2_FMT_SET_DUTY_SEQ_ARG2_ID) {
+        t->duty_steps = data[1];
+        t->duty_rate = data[2];
+        return W4ON2_FMT_SET_DUTY_SEQ_SIZE;
+    } else if (cmd == W4ON2_FMT_SET_MACRO_ARG2_ID) {
+        if (data[1] < W4ON2_MACRO_KIND_COUNT) {
+            t->macros[data[1]] = data[2];
+        }
+        return W4ON2_FMT_SET_MACRO_SIZE;
+    } else if (cmd == W4ON2_FMT_SET_TREMOLO_ARG2_ID) {
+        t->trem_speed = data[1];
+        t->trem_depth = data[2];
+        return W4ON2_FMT_SET_TREMOLO_SIZE;
+    } else if (cmd == W4ON2_FMT_SET_GLIDE_ARG1_ID) {
+        t->glide = data[1];
+        return W4ON2_FMT_SET_GLIDE_SIZE;
+    } else if (cmd == W4ON2_FMT_SLIDE_ARG2_ID) {
+        ch->slide_semitones = data[1];
+        ch->slide_duration = data[2];
+        ch->slide_ticks = 0;
+        return W4ON2_FMT_SLIDE_SIZE;
     }
     return 0;
 }
@@ -285,6 +436,8 @@ void w4on2_player_init(w4on2_player_t *p, const uint8_t *data)
             .outer_data_i = 0,
             .inner_data_i = 0,
             .delay = 0,
+            .transpose = 0,
+            .repeat = 0,
         };
     }
 }
@@ -294,12 +447,13 @@ uint8_t w4on2_player_tick(w4on2_player_t *p, w4on2_rt_t *rt)
     uint16_t sz = (uint16_t)(p->data[0] << 8) | (uint16_t)p->data[1];
     uint8_t pattern_count = p->data[2];
     uint8_t track_count = p->data[3];
-    uint16_t first_track_offset_idx = 4 + pattern_count * 2;
+    uint16_t first_track_offset_idx = W4ON2_HEADER_SIZE + pattern_count * 2;
     uint16_t first_track_start = w4on2_u16be(p->data + first_track_offset_idx);
     uint8_t active_tracks = 0;
+    rt->data = p->data;
     for (uint8_t track_i = 0; track_i < track_count; track_i++) {
         w4on2_player_track_t *pt = &p->tracks[track_i];
-        uint16_t track_offset_idx = 4 + pattern_count * 2 + track_i * 2;
+        uint16_t track_offset_idx = W4ON2_HEADER_SIZE + pattern_count * 2 + track_i * 2;
         uint16_t track_start = w4on2_u16be(p->data + track_offset_idx);
         uint16_t track_end = track_i < track_count - 1 ? w4on2_u16be(p->data + track_offset_idx + 2) : sz;
 
@@ -315,15 +469,40 @@ uint8_t w4on2_player_tick(w4on2_player_t *p, w4on2_rt_t *rt)
 
         // handle events
         while (pt->outer_data_i < track_end) {
+            // prefixes for the next pattern reference
+            if (pt->inner_data_i == 0) {
+                uint8_t prefix = p->data[pt->outer_data_i];
+                if (prefix == W4ON2_TRACK_TRANSPOSE_ARG1_ID) {
+                    pt->transpose = (int8_t)p->data[pt->outer_data_i + 1];
+                    pt->outer_data_i += 2;
+                    continue;
+                } else if (prefix == W4ON2_TRACK_VOLUME_ARG1_ID) {
+                    rt->tracks[track_i].volume_offset = (int8_t)p->data[pt->outer_data_i + 1];
+                    pt->outer_data_i += 2;
+                    continue;
+                } else if (prefix == W4ON2_TRACK_REPEAT_ARG1_ID) {
+                    pt->repeat = p->data[pt->outer_data_i + 1];
+                    pt->outer_data_i += 2;
+                    continue;
+                }
+            }
+
             // get pattern
             uint8_t ptn_i = p->data[pt->outer_data_i];
-            uint16_t ptn_offset_idx = 4 + ptn_i * 2;
+            uint16_t ptn_offset_idx = W4ON2_HEADER_SIZE + ptn_i * 2;
             uint16_t ptn_start = w4on2_u16be(p->data + ptn_offset_idx);
             uint16_t ptn_end = ptn_i < pattern_count - 1 ? w4on2_u16be(p->data + ptn_offset_idx + 2) : first_track_start;
             if (pt->inner_data_i >= ptn_end) {
-                // go to next pattern
                 pt->inner_data_i = 0;
+                if (pt->repeat > 0) {
+                    // play the same pattern again, keeping its prefixes
+                    pt->repeat--;
+                    continue;
+                }
+                // go to next pattern
                 pt->outer_data_i++;
+                pt->transpose = 0;
+                rt->tracks[track_i].volume_offset = 0;
                 continue;
             }
 
@@ -369,6 +548,10 @@ uint8_t w4on2_player_tick(w4on2_player_t *p, w4on2_rt_t *rt)
                     continue; // continue to next event after delay
                 }
                 break; // break from track since we are delaying
+            } else if (pt->transpose != 0 && cmd >= W4ON2_FMT_NOTE_ON_4_START && cmd < W4ON2_FMT_NOTE_ON_4_START + W4ON2_FMT_NOTE_ON_4_COUNT) {
+                int16_t key = (int16_t)(cmd - W4ON2_FMT_NOTE_ON_4_START) + pt->transpose;
+                key = key < 0 ? 0 : key >= W4ON2_FMT_NOTE_ON_4_COUNT ? W4ON2_FMT_NOTE_ON_4_COUNT - 1 : key;
+                pt->inner_data_i += w4on2_rt_feed_event(rt, track_i, &(uint8_t){W4ON2_FMT_NOTE_ON_4_START + key});
             } else {
                 pt->inner_data_i += w4on2_rt_feed_event(rt, track_i, &p->data[pt->inner_data_i]);
             }
@@ -9,13 +9,44 @@
 #define W4ON2_TRACK_COUNT 16
 #define W4ON2_CHANNEL_COUNT 4
 #define W4ON2_MAX_NOTES 8
-#define W4ON2_MAX_PATTERNS 256
+#define W4ON2_NO_KEY 0xff
+#define W4ON2_MAX_PATTERNS 253 // the rest are track list prefixes
+#define W4ON2_MAX_MACROS 255
+#define W4ON2_MAX_MACRO_LENGTH 255
 
 // Volumes
 #define W4ON2_VOLUME_MAX 255
 #define W4ON2_SUSTAIN_MAX 255
 #define W4ON2_VELOCITY_MAX 127
 
+// Duty sequence
+#define W4ON2_DUTY_SEQ_MAX_STEPS 4
+#define W4ON2_DUTY_SEQ_RATE_MAX 63
+
+// Tremolo
+#define W4ON2_TREMOLO_DEPTH_MAX 255
+
+// Track flags on top of the WASM-4 ones
+#define W4ON2_FLAG_RELEASE_EFFECTS 0x80 // keep pitch effects and arpeggio running during Release
+#define W4ON2_FLAG_WASM4_MASK 0x3f
+
+// Macros
+#define W4ON2_MACRO_NONE 0xff
+#define W4ON2_MACRO_VOLUME 0 // [0-255] scales the volume
+#define W4ON2_MACRO_ARPEGGIO 1 // [i8] semitone offset
+#define W4ON2_MACRO_PITCH 2 // [i8] 1/16th semitone offset
+#define W4ON2_MACRO_DUTY 3 // [0-3] pulse duty
+#define W4ON2_MACRO_KIND_COUNT 4
+#define W4ON2_MACRO_VOLUME_MAX 255
+
+// File
+#define W4ON2_HEADER_SIZE 5
+
+// Track pattern list prefixes, applying to the next pattern reference only
+#define W4ON2_TRACK_REPEAT_ARG1_ID 0xfd // [Repeats] - played 1 + Repeats times
+#define W4ON2_TRACK_VOLUME_ARG1_ID 0xfe // [VolumeOffset:i8]
+#define W4ON2_TRACK_TRANSPOSE_ARG1_ID 0xff // [Semitones:i8]
+
 // -----
 // protospan.js format definition
 #define W4ON2_FMT_LONG_DELTA_ARG2_ID 0x00 // [UpperBits][LowerBits]
@@ -64,14 +95,24 @@
 #define W4ON2_FMT_SET_PORTAMENTO_SIZE 2
 #define W4ON2_FMT_SET_VIBRATO_ARG2_ID 0xf5 // [Speed][Depth]
 #define W4ON2_FMT_SET_VIBRATO_SIZE 3
-#define W4ON2_FMT_RESERVED 0xf6
-// Unused values: 9
+#define W4ON2_FMT_SET_DUTY_SEQ_ARG2_ID 0xf6 // [Steps][Rate]
+#define W4ON2_FMT_SET_DUTY_SEQ_SIZE 3
+#define W4ON2_FMT_SET_MACRO_ARG2_ID 0xf7 // [Kind][Macro]
+#define W4ON2_FMT_SET_MACRO_SIZE 3
+#define W4ON2_FMT_SET_TREMOLO_ARG2_ID 0xf8 // [Speed][Depth]
+#define W4ON2_FMT_SET_TREMOLO_SIZE 3
+#define W4ON2_FMT_SET_GLIDE_ARG1_ID 0xf9 // [Glide]
+#define W4ON2_FMT_SET_GLIDE_SIZE 2
+#define W4ON2_FMT_SLIDE_ARG2_ID 0xfa // [Semitones][Ticks]
+#define W4ON2_FMT_SLIDE_SIZE 3
+#define W4ON2_FMT_RESERVED 0xfb
+// Unused values: 4
 // -----
 
 typedef void (*w4on2_tone_t)(uint32_t frequency, uint32_t duration, uint32_t volume, uint32_t flags, void *userdata);
 
 typedef struct {
-    uint8_t flags; // channel, duty, pan according to WASM-4
+    uint8_t flags; // channel, duty, pan according to WASM-4, plus W4ON2_FLAG_*
     uint8_t volume;
     uint8_t velocity;
     uint8_t a, d, s, r;
@@ -79,7 +120,13 @@ typedef struct {
     uint8_t pe_duration;
     uint8_t arp_rate;
     uint8_t portamento;
+    uint8_t glide;
     uint8_t vib_speed, vib_depth;
+    uint8_t trem_speed, trem_depth;
+    uint8_t duty_steps; // 2 bits per step, first step in the lowest bits
+    uint8_t duty_rate; // ticks per step in the lower 6 bits, step count - 1 in the upper 2 bits
+    uint8_t macros[W4ON2_MACRO_KIND_COUNT]; // macro index per kind, or W4ON2_MACRO_NONE
+    int8_t volume_offset; // set by the player for the current pattern reference
 } w4on2_track_t;
 
 typedef struct {
@@ -87,12 +134,19 @@ typedef struct {
     uint8_t last_trigger_ticks; // reset when a new note/key is triggered
     uint8_t active_track_i;
     uint8_t active_key_count;
+    uint8_t released_key_count; // active_key_count at release, keys are kept in note_keys
+    uint16_t release_ticks; // reset when all notes are released
     uint8_t note_keys[W4ON2_MAX_NOTES]; // all active notes (primarily for arpeggio)
+    uint8_t glide_key; // last released note to glide from, or W4ON2_NO_KEY
+    int8_t slide_semitones; // reset on completely new note
+    uint8_t slide_duration;
+    uint8_t slide_ticks; // reset when a slide is triggered
 } w4on2_channel_t;
 
 typedef struct {
     w4on2_tone_t tone;
     void *userdata;
+    const uint8_t *data; // w4on2 binary to look up macros in, set by the player
     w4on2_track_t tracks[W4ON2_TRACK_COUNT];
     w4on2_channel_t channels[W4ON2_CHANNEL_COUNT];
 } w4on2_rt_t;
@@ -108,6 +162,8 @@ typedef struct {
     uint16_t outer_data_i; // index into data
     uint16_t inner_data_i; // index into current pattern data
     uint16_t delay; // delay until next event
+    int8_t transpose; // semitones for the current pattern reference
+    uint8_t repeat; // remaining repeats of the current pattern reference
 } w4on2_player_track_t;
 
 typedef struct {
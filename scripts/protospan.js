@@ -20,6 +20,11 @@ const proto = [
 	['SET_ARP_RATE', 1, 'Rate'],
 	['SET_PORTAMENTO', 1, 'Portamento'],
 	['SET_VIBRATO', 1, 'Speed', 'Depth'],
+	['SET_DUTY_SEQ', 1, 'Steps', 'Rate'],
+	['SET_MACRO', 1, 'Kind', 'Macro'],
+	['SET_TREMOLO', 1, 'Speed', 'Depth'],
+	['SET_GLIDE', 1, 'Glide'],
+	['SLIDE', 1, 'Semitones', 'Ticks'],
 ];
 
 const define = (name, value, comment) => `#define ${name} ${value}${comment ? ' // ' + comment : ''}\n`;
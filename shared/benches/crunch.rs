@@ -0,0 +1,135 @@
+// Crunch timings and sizes for long generated songs
+// Run with `cargo bench -p w4on2_shared`
+
+use std::time::Instant;
+
+use w4on2_shared::{crunch, runtime::W4ON2_MAX_PATTERNS, PatternRef, TrackEvent, TrackEvent::*, W4PlayerSong};
+
+// Small deterministic RNG so fixtures are the same on every run
+struct XorShift(u32);
+impl XorShift {
+    fn next(&mut self, max: u32) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 % max
+    }
+}
+
+fn note(track: &mut Vec<TrackEvent>, key: u8, length: usize) {
+    track.push(NoteOn(key));
+    track.push(DeltaNotesOff(length));
+}
+
+// 4/4 loop with a fill every 8 bars
+fn drums(bars: usize) -> Vec<TrackEvent> {
+    let mut track = vec![];
+    for bar in 0..bars {
+        for step in 0..8 {
+            let fill = bar % 8 == 7 && step >= 4;
+            let key = if fill {
+                50 + step
+            } else if step % 4 == 0 {
+                36
+            } else {
+                42
+            };
+            note(&mut track, key, 6);
+        }
+    }
+    track
+}
+
+// Riff following a chord progression, i.e. transposed repeats
+fn bass(bars: usize) -> Vec<TrackEvent> {
+    let mut track = vec![SetVelocity(100)];
+    for bar in 0..bars {
+        let root = [36, 41, 43, 38][bar % 4];
+        for offset in [0, 0, 12, 7, 0, 10, 12, 7] {
+            note(&mut track, root + offset, 6);
+        }
+    }
+    track
+}
+
+// Phrases which are mostly repeated, with some random variation
+fn lead(bars: usize, rng: &mut XorShift) -> Vec<TrackEvent> {
+    let phrases: Vec<Vec<(u8, usize)>> = (0..4)
+        .map(|_| {
+            (0..6)
+                .map(|_| (60 + rng.next(24) as u8, [6, 12, 18][rng.next(3) as usize]))
+                .collect()
+        })
+        .collect();
+    let mut track = vec![];
+    for bar in 0..bars {
+        for (key, length) in &phrases[(bar / 2) % phrases.len()] {
+            let vary = rng.next(10) == 0;
+            if vary {
+                track.push(SetVelocity(60 + rng.next(60) as u8));
+            }
+            note(&mut track, if vary { key + 2 } else { *key }, *length);
+        }
+    }
+    track
+}
+
+// Random notes, worst case for the cruncher
+fn noise(events: usize, rng: &mut XorShift) -> Vec<TrackEvent> {
+    let mut track = vec![];
+    while track.len() < events {
+        note(&mut track, rng.next(128) as u8, 1 + rng.next(8) as usize);
+    }
+    track
+}
+
+fn bench(name: &str, tracks: Vec<Vec<TrackEvent>>) {
+    let events: usize = tracks.iter().map(|t| t.len()).sum();
+    let uncrunched = W4PlayerSong {
+        tracks: (0..tracks.len()).map(|i| vec![PatternRef::new(i)]).collect(),
+        patterns: tracks.clone(),
+        macros: vec![],
+    }
+    .serialize()
+    .len();
+    let start = Instant::now();
+    let crunched = crunch::crunch_transposed(
+        tracks.clone(),
+        W4ON2_MAX_PATTERNS as usize,
+        2,
+        &mut crunch::CrunchControl::default(),
+    );
+    let elapsed = start.elapsed();
+    assert_eq!(crunch::uncrunch_transposed(&crunched.dict, &crunched.usages), tracks);
+    let size = W4PlayerSong {
+        patterns: crunched.dict,
+        tracks: crunched.usages,
+        macros: vec![],
+    }
+    .serialize()
+    .len();
+    println!(
+        "{:<12} {:>6} events | {:>6} -> {:>5} bytes | {:>8.1} ms",
+        name,
+        events,
+        uncrunched,
+        size,
+        elapsed.as_secs_f64() * 1000.0
+    );
+}
+
+fn main() {
+    let mut rng = XorShift(0x2468ace1);
+    bench("drums", vec![drums(256)]);
+    bench("bass", vec![bass(256)]);
+    bench("lead", vec![lead(256, &mut rng)]);
+    bench("noise", vec![noise(4000, &mut rng)]);
+    bench(
+        "full_song",
+        vec![drums(192), bass(192), lead(192, &mut rng), lead(192, &mut rng)],
+    );
+    bench(
+        "long_song",
+        vec![drums(768), bass(768), lead(768, &mut rng), noise(6000, &mut rng)],
+    );
+}
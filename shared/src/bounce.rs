@@ -1,54 +1,624 @@
+use std::ops::Range;
+
 use crate::*;
 
+// What the `tone` callback plays into
+struct BounceOutput {
+    apu: wasm4_apu::APU,
+    rt: *const w4on2_rt_t,
+    solo_track: Option<u8>, // other tracks are muted
+}
+
 unsafe extern "C" fn bounce_apu_tone(frequency: u32, duration: u32, volume: u32, flags: u32, userdata: *mut c_void) {
-    let apu = unsafe { &mut *(userdata as *mut wasm4_apu::APU) };
-    apu.tone(frequency, duration, volume, flags);
+    let output = unsafe { &mut *(userdata as *mut BounceOutput) };
+    if let Some(solo) = output.solo_track {
+        let channel = unsafe { &(*output.rt).channels[(flags & 0x3) as usize] };
+        if channel.active_track_i != solo {
+            // still cut off the solo track, like the other track does when mixed
+            output.apu.tone(frequency, 0, 0, flags);
+            return;
+        }
+    }
+    output.apu.tone(frequency, duration, volume, flags);
 }
 
 pub const WASM4_SAMPLE_RATE: u32 = 44100;
+pub const MIN_SAMPLE_RATE: u32 = 8000;
+pub const MAX_SAMPLE_RATE: u32 = 192000;
+
+// Five WASM-4 ticks
+pub const DEFAULT_PADDING: f64 = 5.0 / 60.0;
+
+// The longest a tone can last, with attack, decay, sustain and release all at their maximum
+// Anything still sounding after waiting this long for releases is a note which was never released.
+const MAX_RELEASE_TICKS: usize = 4 * 255;
+
+pub const WASM4_CHANNEL_NAMES: [&str; 4] = ["pulse1", "pulse2", "triangle", "noise"];
+
+// Seconds between clipped samples which still counts as the same run
+const CLIP_RUN_GAP: f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SampleFormat {
+    #[default]
+    Int16,
+    Int24,
+    Float32,
+}
+impl SampleFormat {
+    pub fn types() -> [SampleFormat; 3] {
+        [SampleFormat::Int16, SampleFormat::Int24, SampleFormat::Float32]
+    }
+}
+impl Display for SampleFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SampleFormat::Int16 => "16",
+            SampleFormat::Int24 => "24",
+            SampleFormat::Float32 => "32f",
+        })
+    }
+}
+impl std::str::FromStr for SampleFormat {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        SampleFormat::types()
+            .into_iter()
+            .find(|t| t.to_string() == s)
+            .ok_or_else(|| format!("unknown sample format `{}`, expected 16, 24 or 32f", s))
+    }
+}
 
-pub fn bounce_pcm(w4on2_bytes: &[u8]) -> Vec<i16> {
-    const SAMPLES_PER_TICK: u32 = WASM4_SAMPLE_RATE / 60;
-    const PADDING_TICKS: usize = 5;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Normalize {
+    Peak(f64),     // dBFS
+    Loudness(f64), // integrated, in LUFS
+}
 
-    let apu_raw = Box::into_raw(Box::new(wasm4_apu::APU::new(WASM4_SAMPLE_RATE))); // force WASM-4 sample-rate
-    let apu = unsafe { &mut *apu_raw };
-    let (mut rt, mut ply) = unsafe {
-        let mut rt = std::mem::zeroed::<w4on2_rt_t>();
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BounceOptions {
+    pub sample_rate: u32,
+    pub format: SampleFormat,
+    pub dither: bool, // when writing integer samples
+    pub normalize: Option<Normalize>,
+    pub loops: u32,    // times to play the song
+    pub fade_out: f64, // seconds at the end of the last loop
+    pub lead_in: f64,  // seconds of silence before the song
+    pub tail: f64,     // seconds after the song, or after everything is released if `until_released`
+    pub until_released: bool,
+}
+impl Default for BounceOptions {
+    fn default() -> Self {
+        Self {
+            sample_rate: WASM4_SAMPLE_RATE,
+            format: SampleFormat::default(),
+            dither: false,
+            normalize: None,
+            loops: 1,
+            fade_out: 0.0,
+            lead_in: DEFAULT_PADDING,
+            tail: DEFAULT_PADDING,
+            until_released: false,
+        }
+    }
+}
+impl BounceOptions {
+    pub fn validate(&self) -> Result<()> {
+        if !(MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(&self.sample_rate) {
+            anyhow::bail!(
+                "sample rate {} Hz is not in {}..={} Hz",
+                self.sample_rate,
+                MIN_SAMPLE_RATE,
+                MAX_SAMPLE_RATE
+            );
+        }
+        Ok(())
+    }
+}
+
+// Where samples went out of range, as runs of frames
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Clipping {
+    pub sample_rate: u32,
+    pub samples: usize, // counting both channels
+    pub runs: Vec<Range<usize>>,
+}
+impl Clipping {
+    fn add(&mut self, frames: Range<usize>, samples: usize) {
+        self.samples += samples;
+        match self.runs.last_mut() {
+            Some(run) if frames.start <= run.end + (CLIP_RUN_GAP * self.sample_rate as f64) as usize => {
+                run.end = frames.end
+            }
+            _ => self.runs.push(frames),
+        }
+    }
+}
+impl Display for Clipping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const SHOWN_RUNS: usize = 8;
+        let seconds = |frame: usize| format!("{:.3}", frame as f64 / self.sample_rate as f64);
+        write!(f, "{} samples at", self.samples)?;
+        for (i, run) in self.runs.iter().take(SHOWN_RUNS).enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            let (start, end) = (seconds(run.start), seconds(run.end - 1));
+            if start == end {
+                write!(f, "{}{}s", separator, start)?;
+            } else {
+                write!(f, "{}{}-{}s", separator, start, end)?;
+            }
+        }
+        if self.runs.len() > SHOWN_RUNS {
+            write!(f, " and {} more", self.runs.len() - SHOWN_RUNS)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Bounce {
+    pub sample_rate: u32,
+    pub samples: Vec<f32>, // interleaved stereo, where 1.0 is the full scale of the WASM-4 APU
+    pub gain: f64,         // in dB, from normalizing
+    pub peak: f32,
+    pub loudness: Option<f64>, // see `loudness::integrated_loudness`
+    pub clipped: Clipping,     // past full scale after the gain, which integer samples are clipped to
+}
+impl Bounce {
+    fn new(mut samples: Vec<f32>, sample_rate: u32, gain: f64) -> Self {
+        if gain != 0.0 {
+            let scale = 10f64.powf(gain / 20.0) as f32;
+            samples.iter_mut().for_each(|s| *s *= scale);
+        }
+        let mut clipped = Clipping {
+            sample_rate,
+            ..Default::default()
+        };
+        for (frame, pair) in samples.chunks_exact(2).enumerate() {
+            let over = pair.iter().filter(|s| s.abs() > 1.0).count();
+            if over > 0 {
+                clipped.add(frame..frame + 1, over);
+            }
+        }
+        Self {
+            sample_rate,
+            peak: samples.iter().fold(0.0, |peak, s| peak.max(s.abs())),
+            loudness: loudness::integrated_loudness(&samples, sample_rate),
+            samples,
+            gain,
+            clipped,
+        }
+    }
+    // What `normalize` takes, in dB
+    fn normalize_gain(&self, normalize: Option<Normalize>) -> f64 {
+        match normalize {
+            Some(Normalize::Peak(target)) if self.peak > 0.0 => target - 20.0 * (self.peak as f64).log10(),
+            Some(Normalize::Loudness(target)) => self.loudness.map_or(0.0, |loudness| target - loudness),
+            _ => 0.0,
+        }
+    }
+}
+impl Display for Bounce {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.2} seconds at {} Hz, peak {:.2} dBFS",
+            self.samples.len() as f64 / 2.0 / self.sample_rate as f64,
+            self.sample_rate,
+            20.0 * (self.peak as f64).log10()
+        )?;
+        if let Some(loudness) = self.loudness {
+            write!(f, ", loudness {:.2} LUFS", loudness)?;
+        }
+        if self.gain != 0.0 {
+            write!(f, ", gain {:+.2} dB", self.gain)?;
+        }
+        Ok(())
+    }
+}
+
+// The stereo mix, and the output of each channel if `taps`
+// `frames` overrides how long the bounce is, for stems which should line up with the mix.
+fn render(
+    w4on2_bytes: &[u8],
+    options: &BounceOptions,
+    solo_track: Option<u8>,
+    taps: bool,
+    frames: Option<usize>,
+) -> (Vec<f32>, Option<[Vec<f32>; 4]>) {
+    let sample_rate = options.sample_rate;
+    let lead_in = (options.lead_in * sample_rate as f64).round() as usize;
+    let rt_raw = Box::into_raw(Box::new(unsafe { std::mem::zeroed::<w4on2_rt_t>() }));
+    let output_raw = Box::into_raw(Box::new(BounceOutput {
+        apu: wasm4_apu::APU::new(sample_rate),
+        rt: rt_raw,
+        solo_track,
+    }));
+    let output = unsafe { &mut *output_raw };
+    if taps {
+        output.apu.taps = Some(Default::default());
+    }
+    let mut ply = unsafe {
         let mut ply = std::mem::zeroed::<w4on2_player_t>();
-        w4on2_rt_init(&mut rt, Some(bounce_apu_tone), apu_raw as *mut c_void);
+        w4on2_rt_init(rt_raw, Some(bounce_apu_tone), output_raw as *mut c_void);
         w4on2_player_init(&mut ply, w4on2_bytes.as_ptr());
-        (rt, ply)
+        ply
     };
 
     let mut sample_vec = Vec::<i16>::new();
-    let mut sample_buf: [i16; SAMPLES_PER_TICK as usize * 2] = [0; (SAMPLES_PER_TICK as usize * 2)];
-    let mut gen_samples = |rt| {
-        unsafe { w4on2_rt_tick(rt) }
+    let mut sample_buf = vec![0; (sample_rate as usize / 60 + 1) * 2];
+    let mut ticks: u64 = 0;
+    let mut gen_samples = |apu: &mut wasm4_apu::APU, sample_vec: &mut Vec<i16>| {
+        unsafe { w4on2_rt_tick(rt_raw) }
         apu.tick();
-        apu.write_samples(&mut sample_buf, SAMPLES_PER_TICK as usize);
-        sample_vec.extend(sample_buf);
+        // WASM-4 ticks at 60 Hz, which isn't a whole number of samples at every sample rate
+        let frames = ((ticks + 1) * sample_rate as u64 / 60 - ticks * sample_rate as u64 / 60) as usize;
+        ticks += 1;
+        apu.write_samples(&mut sample_buf, frames);
+        sample_vec.extend(&sample_buf[..frames * 2]);
     };
-    for _ in 0..PADDING_TICKS {
-        gen_samples(&mut rt);
-    }
+    // the next loop starts on the next tick, like a game restarting the song, with whatever is still sounding from
+    // the previous one
+    let mut loops = options.loops.max(1);
     loop {
-        gen_samples(&mut rt);
-        if unsafe { w4on2_player_tick(&mut ply, &mut rt) } == 0 {
-            break;
+        gen_samples(&mut output.apu, &mut sample_vec);
+        if unsafe { w4on2_player_tick(&mut ply, rt_raw) } == 0 {
+            loops -= 1;
+            if loops == 0 {
+                break;
+            }
+            unsafe { w4on2_player_init(&mut ply, w4on2_bytes.as_ptr()) };
         }
     }
-    for _ in 0..PADDING_TICKS {
-        gen_samples(&mut rt);
+    let end = sample_vec.len() / 2;
+    if options.until_released && frames.is_none() {
+        for _ in 0..MAX_RELEASE_TICKS {
+            if output.apu.is_silent() {
+                break;
+            }
+            gen_samples(&mut output.apu, &mut sample_vec);
+        }
+    }
+    let tail_end = match frames {
+        Some(frames) => frames.saturating_sub(lead_in),
+        None => sample_vec.len() / 2 + (options.tail * sample_rate as f64).round() as usize,
+    };
+    while sample_vec.len() / 2 < tail_end {
+        gen_samples(&mut output.apu, &mut sample_vec);
     }
+    sample_vec.truncate(tail_end * 2);
 
-    sample_vec
+    let output = unsafe {
+        drop(Box::from_raw(rt_raw));
+        Box::from_raw(output_raw)
+    };
+    let fade = (options.fade_out * sample_rate as f64).round() as usize;
+    let finish = |pcm: Vec<i16>| -> Vec<f32> {
+        let mut samples = vec![0.0; lead_in * 2];
+        samples.extend(pcm.iter().take(tail_end * 2).enumerate().map(|(i, s)| {
+            let frame = i / 2;
+            let gain = if fade == 0 || frame + fade < end {
+                1.0
+            } else {
+                // down to silence at the end of the last loop
+                end.saturating_sub(frame) as f32 / fade as f32
+            };
+            *s as f32 / 32768.0 * gain
+        }));
+        samples
+    };
+    (finish(sample_vec), output.apu.taps.map(|taps| taps.map(finish)))
+}
+
+pub fn bounce(w4on2_bytes: &[u8], options: &BounceOptions) -> Result<Bounce> {
+    options.validate()?;
+    let (pcm, _) = render(w4on2_bytes, options, None, false, None);
+    let mix = Bounce::new(pcm.clone(), options.sample_rate, 0.0);
+    let gain = mix.normalize_gain(options.normalize);
+    Ok(if gain == 0.0 {
+        mix
+    } else {
+        Bounce::new(pcm, options.sample_rate, gain)
+    })
 }
 
-pub fn write_wav<W: std::io::Write + std::io::Seek>(pcm: Vec<i16>, w: &mut W) -> Result<()> {
+// Each WASM-4 channel on its own, and each w4on2 track if `per_track`, leaving out silent ones
+// They get the same gain as the mix, so they still add up to it.
+pub fn bounce_stems(w4on2_bytes: &[u8], per_track: bool, options: &BounceOptions) -> Result<Vec<(String, Bounce)>> {
+    options.validate()?;
+    let (pcm, taps) = render(w4on2_bytes, options, None, true, None);
+    let frames = pcm.len() / 2;
+    let gain = Bounce::new(pcm, options.sample_rate, 0.0).normalize_gain(options.normalize);
+    let mut stems: Vec<(String, Vec<f32>)> = WASM4_CHANNEL_NAMES
+        .iter()
+        .map(|name| name.to_string())
+        .zip(taps.unwrap())
+        .collect();
+    if per_track {
+        for track_i in 0..W4PlayerSong::track_count(w4on2_bytes) {
+            let (pcm, _) = render(w4on2_bytes, options, Some(track_i), false, Some(frames));
+            stems.push((format!("track{}", track_i), pcm));
+        }
+    }
+    Ok(stems
+        .into_iter()
+        .filter(|(_, pcm)| pcm.iter().any(|s| *s != 0.0))
+        .map(|(name, pcm)| (name, Bounce::new(pcm, options.sample_rate, gain)))
+        .collect())
+}
+
+// Triangular dither of one step at most, from a fixed seed so bounces are reproducible
+struct Dither(u32);
+impl Dither {
+    fn next(&mut self) -> f32 {
+        let mut uniform = || {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as f32 / u32::MAX as f32
+        };
+        uniform() - uniform()
+    }
+}
+
+// Integer samples are clipped to their range
+pub fn write_wav<W: std::io::Write + std::io::Seek>(bounce: &Bounce, options: &BounceOptions, w: &mut W) -> Result<()> {
+    let mut dither = Dither(0x5eed);
+    let mut quantize = |s: f32, max: f32| {
+        let noise = if options.dither { dither.next() } else { 0.0 };
+        (s * max + noise).round().clamp(-max, max - 1.0)
+    };
+    let (format, bits, data) = match options.format {
+        SampleFormat::Int16 => (
+            wav::WAV_FORMAT_PCM,
+            16,
+            wav::BitDepth::Sixteen(bounce.samples.iter().map(|s| quantize(*s, 32768.0) as i16).collect()),
+        ),
+        SampleFormat::Int24 => (
+            wav::WAV_FORMAT_PCM,
+            24,
+            wav::BitDepth::TwentyFour(bounce.samples.iter().map(|s| quantize(*s, 8388608.0) as i32).collect()),
+        ),
+        SampleFormat::Float32 => (
+            wav::WAV_FORMAT_IEEE_FLOAT,
+            32,
+            wav::BitDepth::ThirtyTwoFloat(bounce.samples.clone()),
+        ),
+    };
     Ok(wav::write(
-        wav::Header::new(wav::WAV_FORMAT_PCM, 2, WASM4_SAMPLE_RATE, 16),
-        &wav::BitDepth::Sixteen(pcm),
+        wav::Header::new(format, 2, bounce.sample_rate, bits),
+        &data,
         w,
     )?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A pulse and a triangle note on their own tracks, 30 ticks long
+    fn two_notes() -> Vec<u8> {
+        let note = |flags: u8, key: u8| {
+            vec![
+                TrackEvent::SetFlags(flags),
+                TrackEvent::NoteOn(key),
+                TrackEvent::DeltaNotesOff(20),
+                TrackEvent::Delta(10),
+            ]
+        };
+        W4PlayerSong {
+            patterns: vec![
+                note(Channel::Pulse1(PulseDuty::D25).to_wasm4_flags(), 60),
+                note(Channel::Triangle.to_wasm4_flags(), 40),
+            ],
+            tracks: vec![vec![PatternRef::new(0)], vec![PatternRef::new(1)]],
+            macros: vec![],
+        }
+        .serialize()
+    }
+
+    #[test]
+    fn test_stems() {
+        let song = two_notes();
+        assert_eq!(W4PlayerSong::track_count(&song), 2);
+        let options = BounceOptions::default();
+        let mix = bounce(&song, &options).unwrap().samples;
+        let stems = bounce_stems(&song, true, &options).unwrap();
+        let names: Vec<&str> = stems.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["pulse1", "triangle", "track0", "track1"]);
+        let sum = |stems: &[(String, Bounce)]| -> Vec<f32> {
+            (0..mix.len())
+                .map(|i| stems.iter().map(|(_, stem)| stem.samples[i]).sum())
+                .collect()
+        };
+        assert_eq!(sum(&stems[..2]), mix);
+        assert_eq!(sum(&stems[2..]), mix);
+    }
+
+    #[test]
+    fn test_bounce_options() {
+        let song = two_notes();
+        let default = bounce(&song, &BounceOptions::default()).unwrap();
+        let options = BounceOptions {
+            sample_rate: 48000,
+            normalize: Some(Normalize::Peak(-1.0)),
+            ..Default::default()
+        };
+        let normalized = bounce(&song, &options).unwrap();
+        assert_eq!(normalized.samples.len(), default.samples.len() * 48000 / 44100);
+        assert!((20.0 * (normalized.peak as f64).log10() + 1.0).abs() < 0.01);
+
+        let mut wav_bytes = std::io::Cursor::new(vec![]);
+        let options = BounceOptions {
+            format: SampleFormat::Int24,
+            ..Default::default()
+        };
+        write_wav(&default, &options, &mut wav_bytes).unwrap();
+        wav_bytes.set_position(0);
+        let (header, data) = wav::read(&mut wav_bytes).unwrap();
+        assert_eq!(header.bits_per_sample, 24);
+        // 16 bit samples are shifted up without changing
+        let samples = data.as_twenty_four().unwrap();
+        assert!(samples
+            .iter()
+            .zip(&default.samples)
+            .all(|(a, b)| *a == (*b * 8388608.0) as i32));
+
+        for sample_rate in [0, 1, 59, MAX_SAMPLE_RATE + 1] {
+            let options = BounceOptions {
+                sample_rate,
+                ..Default::default()
+            };
+            assert!(bounce(&song, &options).is_err());
+            assert!(bounce_stems(&song, false, &options).is_err());
+        }
+        let lowest = BounceOptions {
+            sample_rate: MIN_SAMPLE_RATE,
+            ..Default::default()
+        };
+        assert!(!bounce(&song, &lowest).unwrap().samples.is_empty());
+    }
+
+    #[test]
+    fn test_loops_and_padding() {
+        let song = two_notes();
+        let bare = BounceOptions {
+            lead_in: 0.0,
+            tail: 0.0,
+            ..Default::default()
+        };
+        // whole ticks of 735 frames in two channels
+        let once = bounce(&song, &bare).unwrap().samples;
+        assert_eq!(once.len() % (2 * 735), 0);
+        let twice = bounce(&song, &BounceOptions { loops: 2, ..bare }).unwrap().samples;
+        assert_eq!(twice.len(), 2 * once.len());
+        assert_eq!(twice[..once.len()], once);
+
+        let padded = bounce(&song, &BounceOptions { lead_in: 0.5, ..bare }).unwrap().samples;
+        assert!(padded[..44100].iter().all(|x| *x == 0.0));
+        assert_eq!(padded[44100..], once);
+
+        let faded = bounce(&song, &BounceOptions { fade_out: 0.25, ..bare })
+            .unwrap()
+            .samples;
+        assert_eq!(faded.len(), once.len());
+        assert_eq!(faded[..once.len() - 44100 / 2], once[..once.len() - 44100 / 2]);
+        assert!(faded[faded.len() - 2..].iter().all(|x| x.abs() < 0.001));
+
+        let released = bounce(
+            &song,
+            &BounceOptions {
+                until_released: true,
+                ..bare
+            },
+        )
+        .unwrap()
+        .samples;
+        assert!(released.len() >= once.len());
+        assert_eq!(released[..once.len()], once);
+
+        // a note which is never released doesn't keep the bounce going forever
+        let held = W4PlayerSong {
+            patterns: vec![vec![
+                TrackEvent::SetFlags(Channel::Triangle.to_wasm4_flags()),
+                TrackEvent::NoteOn(40),
+                TrackEvent::Delta(10),
+            ]],
+            tracks: vec![vec![PatternRef::new(0)]],
+            macros: vec![],
+        }
+        .serialize();
+        let options = BounceOptions {
+            until_released: true,
+            ..bare
+        };
+        let once = bounce(&held, &bare).unwrap().samples;
+        assert_eq!(
+            bounce(&held, &options).unwrap().samples.len(),
+            once.len() + 2 * MAX_RELEASE_TICKS * 735
+        );
+    }
+
+    #[test]
+    fn test_pattern_volume() {
+        // the pulse pattern twice, the first time with a volume offset
+        let song = |volume: i8| {
+            W4PlayerSong {
+                patterns: vec![vec![
+                    TrackEvent::SetFlags(Channel::Pulse1(PulseDuty::D25).to_wasm4_flags()),
+                    TrackEvent::NoteOn(60),
+                    TrackEvent::DeltaNotesOff(20),
+                    TrackEvent::Delta(10),
+                ]],
+                tracks: vec![vec![
+                    PatternRef {
+                        volume,
+                        ..PatternRef::new(0)
+                    },
+                    PatternRef::new(0),
+                ]],
+                macros: vec![],
+            }
+            .serialize()
+        };
+        let bare = BounceOptions {
+            lead_in: 0.0,
+            tail: 0.0,
+            ..Default::default()
+        };
+        let plain = bounce(&song(0), &bare).unwrap().samples;
+        let quieter = bounce(&song(-100), &bare).unwrap().samples;
+        assert_eq!(quieter.len(), plain.len());
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let half = 30 * 735 * 2;
+        assert!(peak(&quieter[..half]) < peak(&plain[..half]) * 0.8);
+        // the offset only lasts for its pattern
+        assert_eq!(quieter[half..], plain[half..]);
+    }
+
+    #[test]
+    fn test_mix_headroom() {
+        // every channel at full volume and sustained, in both speakers
+        let mut apu = wasm4_apu::APU::new(WASM4_SAMPLE_RATE);
+        for (channel, frequency) in [(0, 440), (1, 660), (2, 110), (3, 2000)] {
+            apu.tone(frequency, 255, 100, channel | 0x4);
+        }
+        let mut samples = vec![0; 44100 * 2];
+        apu.write_samples(&mut samples, 44100);
+        let peak = samples.iter().map(|s| s.unsigned_abs()).max().unwrap();
+        assert!(peak > 20000 && peak <= 22937, "{}", peak);
+    }
+
+    #[test]
+    fn test_clipping() {
+        let song = two_notes();
+        let quiet = bounce(&song, &BounceOptions::default()).unwrap();
+        assert_eq!(quiet.clipped.samples, 0);
+        let options = BounceOptions {
+            normalize: Some(Normalize::Peak(6.0)),
+            ..Default::default()
+        };
+        let loud = bounce(&song, &options).unwrap();
+        let over = loud.samples.iter().filter(|s| s.abs() > 1.0).count();
+        assert!(over > 0);
+        assert_eq!(loud.clipped.samples, over);
+        // nothing plays during the lead in
+        let lead_in = (DEFAULT_PADDING * WASM4_SAMPLE_RATE as f64).round() as usize;
+        assert!(loud.clipped.runs[0].start >= lead_in);
+
+        let clipping = Clipping {
+            sample_rate: 1000,
+            samples: 3,
+            runs: vec![500..501, 1000..1002, 2000..2001],
+        };
+        assert_eq!(clipping.to_string(), "3 samples at 0.500s, 1.000-1.001s, 2.000s");
+        let mut merged = Clipping {
+            sample_rate: 1000,
+            ..Default::default()
+        };
+        merged.add(0..1, 2);
+        merged.add(10..11, 1);
+        merged.add(30..31, 1);
+        assert_eq!(merged.samples, 4);
+        assert_eq!(merged.runs, [0..11, 30..31]);
+    }
+}
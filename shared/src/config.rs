@@ -0,0 +1,679 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    fs,
+    path::Path,
+};
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use toml::{Table, Value};
+
+use crate::*;
+
+// Written by `SongConfig::to_toml`, older versions are migrated when loaded
+pub const SONG_CONFIG_VERSION: i64 = 2;
+
+// Each one takes a config from version i+1 to i+2
+type Migration = fn(&mut Table) -> Result<(), ConfigError>;
+const MIGRATIONS: [Migration; 1] = [migrate_channel_array];
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+lazy_static! {
+    // Checked by `test_builtin_presets`
+    pub static ref BUILTIN_PRESETS: BTreeMap<String, SongTrackConfig> = {
+        let presets: Table = toml::from_str(include_str!("presets.toml")).unwrap();
+        presets
+            .into_iter()
+            .map(|(name, preset)| (name, preset.try_into().unwrap()))
+            .collect()
+    };
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Toml(toml::de::Error),
+    UnsupportedVersion(i64),
+    Invalid(String), // e.g. an unknown key outside of the channels
+    InvalidChannelKey(String),
+    Channel(usize, String), // the channel couldn't be deserialized, e.g. because of an unknown or mistyped field
+    Preset(String, String), // like `Channel`
+    UnknownPreset(String, String), // where it was used, and its name
+    Include(String, String), // the path, and what went wrong with it
+    Automation(usize, String), // index, and what's wrong with it
+    OutOfRange {
+        channel: usize,
+        field: String,
+        value: usize,
+        max: usize,
+    },
+}
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Toml(err) => write!(f, "{}", err),
+            ConfigError::UnsupportedVersion(version) => write!(
+                f,
+                "version {} is not supported, the newest is {}",
+                version, SONG_CONFIG_VERSION
+            ),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+            ConfigError::InvalidChannelKey(key) => {
+                write!(f, "channels.{}: channels are keyed by MIDI channel, 0 to 15", key)
+            }
+            ConfigError::Channel(channel, msg) => write!(f, "channels.{}: {}", channel, msg.trim()),
+            ConfigError::Preset(name, msg) => write!(f, "presets.{}: {}", name, msg.trim()),
+            ConfigError::UnknownPreset(at, name) => write!(f, "{}: unknown preset `{}`", at, name),
+            ConfigError::Include(path, msg) => write!(f, "include `{}`: {}", path, msg.trim()),
+            ConfigError::Automation(i, msg) => write!(f, "automation #{}: {}", i + 1, msg.trim()),
+            ConfigError::OutOfRange {
+                channel,
+                field,
+                value,
+                max,
+            } => write!(
+                f,
+                "channels.{}.{}: {} is over the maximum of {}",
+                channel, field, value, max
+            ),
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
+// Version 1 listed all 16 channels as `[[channels]]`, version 2 has `[channels.N]` for the ones that are used
+fn migrate_channel_array(conf: &mut Table) -> Result<(), ConfigError> {
+    let Some(Value::Array(channels)) = conf.remove("channels") else {
+        return Ok(());
+    };
+    if channels.len() > 16 {
+        return Err(ConfigError::Invalid(format!(
+            "{} channels, there can be at most 16",
+            channels.len()
+        )));
+    }
+    let sparse = channels
+        .into_iter()
+        .enumerate()
+        .filter(|(_, ch)| !matches!(ch, Value::Table(t) if t.is_empty()))
+        .map(|(i, ch)| (i.to_string(), ch))
+        .collect();
+    conf.insert("channels".to_owned(), Value::Table(sparse));
+    Ok(())
+}
+
+impl Automation {
+    fn validate(&self) -> Result<(), String> {
+        if self.channel >= 16 {
+            return Err(format!("channel {} doesn't exist, there are 16 from 0", self.channel));
+        }
+        if self.bar == Some(0) || self.beat.is_some_and(|beat| beat < 1.0) {
+            return Err("bars and beats are counted from 1".to_owned());
+        }
+        match (self.bar, &self.marker) {
+            (Some(_), None) => Ok(()),
+            (None, Some(_)) if self.beat.is_some() => Err("`beat` is only used with `bar`".to_owned()),
+            (None, Some(_)) => Ok(()),
+            _ => Err("needs either a `bar` or a `marker`".to_owned()),
+        }
+    }
+}
+
+impl SongTrackConfig {
+    // Limits which aren't already enforced by the types
+    fn validate(&self, channel: usize) -> Result<(), ConfigError> {
+        let check = |field: &str, value: usize, max: u32| {
+            if value > max as usize {
+                Err(ConfigError::OutOfRange {
+                    channel,
+                    field: field.to_owned(),
+                    value,
+                    max: max as usize,
+                })
+            } else {
+                Ok(())
+            }
+        };
+        check("volume", self.volume as usize, W4ON2_VOLUME_MAX)?;
+        check("adsr.sustain", self.adsr.2 as usize, W4ON2_SUSTAIN_MAX)?;
+        check("tremolo.depth", self.tremolo.depth as usize, W4ON2_TREMOLO_DEPTH_MAX)?;
+        check(
+            "duty_sequence.rate",
+            self.duty_sequence.rate as usize,
+            W4ON2_DUTY_SEQ_RATE_MAX,
+        )?;
+        check(
+            "duty_sequence.steps",
+            self.duty_sequence.steps.len(),
+            W4ON2_DUTY_SEQ_MAX_STEPS,
+        )?;
+        let macros = [
+            ("macros.volume", self.macros.volume.as_ref().map(|m| m.to_data())),
+            ("macros.arpeggio", self.macros.arpeggio.as_ref().map(|m| m.to_data())),
+            ("macros.pitch", self.macros.pitch.as_ref().map(|m| m.to_data())),
+            ("macros.duty", self.macros.duty.as_ref().map(|m| m.to_data())),
+        ];
+        for (field, data) in macros {
+            let Some(Some(data)) = data else {
+                continue;
+            };
+            check(&format!("{}.values", field), data.values.len(), W4ON2_MAX_MACRO_LENGTH)?;
+            let last = data.values.len() as u32 - 1;
+            if let Some(point) = data.loop_point {
+                check(&format!("{}.loop", field), point as usize, last)?;
+            }
+            if let Some(point) = data.release_point {
+                check(&format!("{}.release", field), point as usize, last)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Merges `over` into `base`, where tables like `vibrato` are merged field by field
+// The channel type and each macro are replaced as a whole, since mixing them doesn't make sense.
+fn merge_tables(base: &mut Table, over: Table, nested: bool) {
+    for (key, value) in over {
+        if let Value::Table(over) = value {
+            if !nested && key != "channel" {
+                if let Some(Value::Table(base)) = base.get_mut(&key) {
+                    merge_tables(base, over, true);
+                    continue;
+                }
+            }
+            base.insert(key, Value::Table(over));
+        } else {
+            base.insert(key, value);
+        }
+    }
+}
+
+// An empty macro turns off the one of a preset, as TOML has no other way of leaving it out
+fn drop_empty_macros(table: &mut Table) {
+    if let Some(Value::Table(macros)) = table.get_mut("macros") {
+        macros.retain(|_, m| !matches!(m.get("values"), Some(Value::Array(values)) if values.is_empty()));
+    }
+}
+
+fn take_includes(table: &mut Table) -> Result<Vec<String>, ConfigError> {
+    match table.remove("include") {
+        Some(include) => include
+            .try_into()
+            .map_err(|_| ConfigError::Invalid("include must be a list of paths".to_owned())),
+        None => Ok(vec![]),
+    }
+}
+
+fn take_presets(table: &mut Table) -> Result<Table, ConfigError> {
+    match table.remove("presets") {
+        Some(Value::Table(presets)) => Ok(presets),
+        Some(_) => Err(ConfigError::Invalid("presets must be a table".to_owned())),
+        None => Ok(Table::new()),
+    }
+}
+
+// Collects the presets of included files, where later ones replace earlier ones
+fn collect_included(includes: &[String], dir: &Path, depth: usize, into: &mut Table) -> Result<(), ConfigError> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(ConfigError::Invalid("includes are nested too deep".to_owned()));
+    }
+    for include in includes {
+        let err = |msg: String| ConfigError::Include(include.clone(), msg);
+        let path = dir.join(include);
+        let toml_str = fs::read_to_string(&path).map_err(|e| err(e.to_string()))?;
+        let mut table: Table = toml::from_str(&toml_str).map_err(|e| err(e.to_string()))?;
+        table.remove("version");
+        let nested = take_includes(&mut table).map_err(|e| err(e.to_string()))?;
+        collect_included(&nested, path.parent().unwrap_or(dir), depth + 1, into)?;
+        into.extend(take_presets(&mut table).map_err(|e| err(e.to_string()))?);
+        if let Some(key) = table.keys().next() {
+            return Err(err(format!(
+                "unknown key `{}`, only `include` and `presets` are used from included files",
+                key
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Resolves preset inheritance, preferring presets from the file itself, then included ones, then built-in ones.
+// A preset inheriting from its own name is based on the one it hides, e.g. a `kick` which changes the built-in one.
+struct PresetResolver<'a> {
+    layers: [&'a Table; 2], // presets of the file itself, and the included ones
+    resolved: BTreeMap<String, SongTrackConfig>,
+}
+impl PresetResolver<'_> {
+    fn resolve(
+        &mut self,
+        name: &str,
+        at: &str,
+        stack: &mut Vec<(String, usize)>,
+    ) -> Result<SongTrackConfig, ConfigError> {
+        self.resolve_from(name, 0, at, stack)
+    }
+    fn resolve_from(
+        &mut self,
+        name: &str,
+        first_layer: usize,
+        at: &str,
+        stack: &mut Vec<(String, usize)>,
+    ) -> Result<SongTrackConfig, ConfigError> {
+        if let Some(preset) = self.resolved.get(name).filter(|_| first_layer == 0) {
+            return Ok(preset.clone());
+        }
+        let Some((layer, value)) = (first_layer..self.layers.len()).find_map(|i| Some((i, self.layers[i].get(name)?)))
+        else {
+            return BUILTIN_PRESETS
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ConfigError::UnknownPreset(at.to_owned(), name.to_owned()));
+        };
+        let Value::Table(table) = value else {
+            return Err(ConfigError::Preset(name.to_owned(), "must be a table".to_owned()));
+        };
+        if stack.iter().any(|(n, l)| n == name && *l == layer) {
+            return Err(ConfigError::Preset(name.to_owned(), "inherits from itself".to_owned()));
+        }
+        stack.push((name.to_owned(), layer));
+        let merged = self.inherit(table.clone(), &format!("presets.{}", name), stack)?;
+        stack.pop();
+        let preset: SongTrackConfig = Value::Table(merged)
+            .try_into()
+            .map_err(|err: toml::de::Error| ConfigError::Preset(name.to_owned(), err.to_string()))?;
+        if first_layer == 0 {
+            self.resolved.insert(name.to_owned(), preset.clone());
+        }
+        Ok(preset)
+    }
+    // The table on top of the one of its preset, if it has one
+    fn inherit(&mut self, mut table: Table, at: &str, stack: &mut Vec<(String, usize)>) -> Result<Table, ConfigError> {
+        let base = match table.get("preset") {
+            Some(Value::String(preset)) => match stack.last() {
+                Some((name, layer)) if name == preset => self.resolve_from(preset, layer + 1, at, stack)?,
+                _ => self.resolve(preset, at, stack)?,
+            },
+            Some(_) => return Err(ConfigError::Invalid(format!("{}.preset must be a name", at))),
+            None => {
+                drop_empty_macros(&mut table);
+                return Ok(table);
+            }
+        };
+        let Ok(Value::Table(mut base)) = Value::try_from(base) else {
+            unreachable!();
+        };
+        merge_tables(&mut base, table, false);
+        drop_empty_macros(&mut base);
+        Ok(base)
+    }
+}
+
+impl SongTrackConfig {
+    // With the fields of `set`, merged like a channel over its preset
+    pub fn with_changes(&self, set: &Table) -> Result<SongTrackConfig, String> {
+        if let Some(key) = ["nickname", "midi_track", "match_nickname", "preset"]
+            .iter()
+            .find(|key| set.contains_key(**key))
+        {
+            return Err(format!("`{}` can't be automated", key));
+        }
+        let Ok(Value::Table(mut table)) = Value::try_from(self) else {
+            unreachable!();
+        };
+        merge_tables(&mut table, set.clone(), false);
+        drop_empty_macros(&mut table);
+        Value::Table(table)
+            .try_into()
+            .map_err(|err: toml::de::Error| err.to_string())
+    }
+    // Takes the instrument from a preset, but keeps what decides which notes this channel gets
+    pub fn apply_preset(&mut self, name: &str, preset: &SongTrackConfig) {
+        *self = SongTrackConfig {
+            nickname: std::mem::take(&mut self.nickname),
+            midi_track: self.midi_track.take(),
+            match_nickname: self.match_nickname,
+            preset: Some(name.to_owned()),
+            ..preset.clone()
+        };
+    }
+}
+
+impl SongConfig {
+    // Includes are relative to the current directory
+    pub fn from_toml(toml_str: &str) -> Result<Self, ConfigError> {
+        Self::from_toml_in(toml_str, Path::new(""))
+    }
+    // Includes are relative to `dir`, which should be where the TOML file is
+    pub fn from_toml_in(toml_str: &str, dir: &Path) -> Result<Self, ConfigError> {
+        let mut table: Table = toml::from_str(toml_str).map_err(ConfigError::Toml)?;
+        let version = match table.remove("version") {
+            Some(Value::Integer(version)) => version,
+            Some(_) => return Err(ConfigError::Invalid("version must be an integer".to_owned())),
+            // files from before there was a version have a channel array
+            None if matches!(table.get("channels"), Some(Value::Array(_))) => 1,
+            None => SONG_CONFIG_VERSION,
+        };
+        if !(1..=SONG_CONFIG_VERSION).contains(&version) {
+            return Err(ConfigError::UnsupportedVersion(version));
+        }
+        for migrate in &MIGRATIONS[version as usize - 1..] {
+            migrate(&mut table)?;
+        }
+
+        let mut conf = SongConfig {
+            includes: take_includes(&mut table)?,
+            ..Default::default()
+        };
+        let mut included = Table::new();
+        collect_included(&conf.includes, dir, 0, &mut included)?;
+        let local = take_presets(&mut table)?;
+        // checked against the channels, so they're read last
+        let automation = table.remove("automation");
+        let mut resolver = PresetResolver {
+            layers: [&local, &included],
+            resolved: BTreeMap::new(),
+        };
+        for name in local.keys() {
+            let preset = resolver.resolve(name, "presets", &mut vec![])?;
+            conf.presets.insert(name.clone(), preset);
+        }
+        for name in included.keys() {
+            let preset = resolver.resolve_from(name, 1, "include", &mut vec![])?;
+            conf.included_presets.insert(name.clone(), preset);
+        }
+
+        for (key, value) in table {
+            match key.as_str() {
+                "quantize" => {
+                    conf.quantize = value
+                        .try_into()
+                        .map_err(|err| ConfigError::Invalid(format!("quantize: {}", err).trim().to_owned()))?
+                }
+                "channels" => {
+                    let Value::Table(channels) = value else {
+                        return Err(ConfigError::Invalid("channels must be a table".to_owned()));
+                    };
+                    for (key, value) in channels {
+                        let channel = key
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|ch| *ch < 16)
+                            .ok_or(ConfigError::InvalidChannelKey(key))?;
+                        let Value::Table(value) = value else {
+                            return Err(ConfigError::Channel(channel, "must be a table".to_owned()));
+                        };
+                        let merged = resolver.inherit(value, &format!("channels.{}", channel), &mut vec![])?;
+                        let track: SongTrackConfig = Value::Table(merged)
+                            .try_into()
+                            .map_err(|err: toml::de::Error| ConfigError::Channel(channel, err.to_string()))?;
+                        track.validate(channel)?;
+                        conf.channels[channel] = track;
+                    }
+                }
+                key => return Err(ConfigError::Invalid(format!("unknown key `{}`", key))),
+            }
+        }
+        if let Some(automation) = automation {
+            let Value::Array(automation) = automation else {
+                return Err(ConfigError::Invalid("automation must be a list".to_owned()));
+            };
+            for (i, value) in automation.into_iter().enumerate() {
+                let err = |msg: String| ConfigError::Automation(i, msg);
+                let auto: Automation = value.try_into().map_err(|e: toml::de::Error| err(e.to_string()))?;
+                auto.validate().map_err(err)?;
+                let changed = conf.channels[auto.channel].with_changes(&auto.set).map_err(err)?;
+                changed
+                    .validate(auto.channel)
+                    .map_err(|e| ConfigError::Automation(i, e.to_string()))?;
+                conf.automation.push(auto);
+            }
+        }
+        Ok(conf)
+    }
+    // Presets of this file, then included ones, then built-in ones
+    pub fn preset(&self, name: &str) -> Option<&SongTrackConfig> {
+        self.presets
+            .get(name)
+            .or(self.included_presets.get(name))
+            .or(BUILTIN_PRESETS.get(name))
+    }
+    // Every preset which can be used by name, sorted by it
+    pub fn preset_library(&self) -> Vec<(String, SongTrackConfig)> {
+        let names: BTreeSet<&String> = self
+            .presets
+            .keys()
+            .chain(self.included_presets.keys())
+            .chain(BUILTIN_PRESETS.keys())
+            .collect();
+        names
+            .into_iter()
+            .map(|name| (name.clone(), self.preset(name).unwrap().clone()))
+            .collect()
+    }
+    // Only what differs from the preset of `track`, or from the defaults
+    fn track_table(&self, track: &SongTrackConfig, own_name: Option<&str>) -> Result<Table> {
+        let base = match track.preset.as_deref() {
+            Some(name) if Some(name) == own_name => self.included_presets.get(name).or(BUILTIN_PRESETS.get(name)),
+            Some(name) => self.preset(name),
+            None => None,
+        };
+        let base = base.unwrap_or(&SONG_TRACK_CONFIG_DEFAULT);
+        let (Value::Table(base), Value::Table(mut table)) = (Value::try_from(base)?, Value::try_from(track)?) else {
+            unreachable!();
+        };
+        table.retain(|key, value| key == "preset" || base.get(key) != Some(value));
+        // macros of the preset which this doesn't have are written as empty, see `drop_empty_macros`
+        if let (Some(Value::Table(base_macros)), Some(Value::Table(macros))) =
+            (base.get("macros"), table.get_mut("macros"))
+        {
+            for key in base_macros.keys() {
+                if !macros.contains_key(key) {
+                    let mut empty = Table::new();
+                    empty.insert("values".to_owned(), Value::Array(vec![]));
+                    macros.insert(key.clone(), Value::Table(empty));
+                }
+            }
+        }
+        Ok(table)
+    }
+    // Only writes what differs from the defaults
+    pub fn to_toml(&self) -> Result<String> {
+        let mut table = Table::new();
+        table.insert("version".to_owned(), Value::Integer(SONG_CONFIG_VERSION));
+        if !self.includes.is_empty() {
+            table.insert("include".to_owned(), Value::try_from(&self.includes)?);
+        }
+        if self.quantize != QuantizeConfig::default() {
+            table.insert("quantize".to_owned(), Value::try_from(&self.quantize)?);
+        }
+        if !self.presets.is_empty() {
+            let mut presets = Table::new();
+            for (name, preset) in &self.presets {
+                presets.insert(name.clone(), Value::Table(self.track_table(preset, Some(name))?));
+            }
+            table.insert("presets".to_owned(), Value::Table(presets));
+        }
+        let mut channels = Table::new();
+        for (i, ch) in self.channels.iter().enumerate() {
+            let ch = self.track_table(ch, None)?;
+            if !ch.is_empty() {
+                channels.insert(i.to_string(), Value::Table(ch));
+            }
+        }
+        table.insert("channels".to_owned(), Value::Table(channels));
+        if !self.automation.is_empty() {
+            table.insert("automation".to_owned(), Value::try_from(&self.automation)?);
+        }
+        Ok(toml::to_string(&table)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_channel_array() {
+        let mut old = "[[channels]]\nvolume = 10\n".to_owned();
+        old += &"[[channels]]\n".repeat(2);
+        old += "[[channels]]\nchannel = \"noise\"\n";
+        let conf = SongConfig::from_toml(&old).unwrap();
+        assert_eq!(conf.channels[0].volume, 10);
+        assert_eq!(conf.channels[3].channel, Channel::Noise);
+
+        let saved = conf.to_toml().unwrap();
+        assert!(saved.starts_with("version = 2\n"));
+        let loaded = SongConfig::from_toml(&saved).unwrap();
+        assert_eq!(loaded.channels[0].volume, 10);
+        assert_eq!(loaded.channels[3].channel, Channel::Noise);
+        assert_eq!(SongConfig::from_toml(&saved).unwrap().to_toml().unwrap(), saved);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |toml: &str| SongConfig::from_toml(toml).err().unwrap().to_string();
+        assert!(err("[channels.3]\nsustian = 1").starts_with("channels.3: unknown field `sustian`"));
+        assert!(err("[channels.2]\nadsr = [0, 0, 300, 0]").starts_with("channels.2: "));
+        assert_eq!(
+            err("[channels.16]"),
+            "channels.16: channels are keyed by MIDI channel, 0 to 15"
+        );
+        assert_eq!(err("version = 3"), "version 3 is not supported, the newest is 2");
+        assert_eq!(err("chanels = []"), "unknown key `chanels`");
+        assert_eq!(
+            err("[channels.1.duty_sequence]\nrate = 1\nsteps = [\"25%\", \"50%\", \"75%\", \"25%\", \"50%\"]"),
+            "channels.1.duty_sequence.steps: 5 is over the maximum of 4"
+        );
+        assert_eq!(
+            err("[channels.0]\npreset = \"snar\""),
+            "channels.0: unknown preset `snar`"
+        );
+        assert_eq!(
+            err("[presets.a]\npreset = \"b\"\n[presets.b]\npreset = \"a\""),
+            "presets.a: inherits from itself"
+        );
+        assert_eq!(
+            err("[[automation]]\nchannel = 0\nset = {}"),
+            "automation #1: needs either a `bar` or a `marker`"
+        );
+        assert_eq!(
+            err("[[automation]]\nbar = 2\nchannel = 3\nset = {duty_sequence = {rate = 64}}"),
+            "automation #1: channels.3.duty_sequence.rate: 64 is over the maximum of 63"
+        );
+        assert_eq!(
+            err("[[automation]]\nmarker = \"A\"\nchannel = 1\nset = {nickname = \"B\"}"),
+            "automation #1: `nickname` can't be automated"
+        );
+    }
+
+    #[test]
+    fn test_builtin_presets() {
+        assert!(BUILTIN_PRESETS.len() >= 8);
+        for (name, preset) in BUILTIN_PRESETS.iter() {
+            preset.validate(0).unwrap_or_else(|err| panic!("{}: {}", name, err));
+        }
+    }
+
+    #[test]
+    fn test_presets() {
+        let toml = r#"
+            [presets.soft_snare]
+            preset = "snare"
+            volume = 100
+
+            [channels.0]
+            preset = "lead"
+            vibrato = {depth = 10}
+
+            [channels.9]
+            preset = "soft_snare"
+            adsr = [0, 5, 0, 0]
+        "#;
+        let conf = SongConfig::from_toml(toml).unwrap();
+        let lead = &BUILTIN_PRESETS["lead"];
+        assert_eq!(conf.channels[0].channel, lead.channel);
+        assert_eq!(conf.channels[0].vibrato.speed, lead.vibrato.speed);
+        assert_eq!(conf.channels[0].vibrato.depth, 10);
+        assert_eq!(conf.channels[9].channel, Channel::Noise);
+        assert_eq!(conf.channels[9].volume, 100);
+        assert_eq!(conf.channels[9].adsr, ADSR(0, 5, 0, 0));
+
+        // only the overrides are saved
+        let saved = conf.to_toml().unwrap();
+        assert!(saved.contains("[channels.9]\nadsr = [0, 5, 0, 0]\npreset = \"soft_snare\"\n"));
+        assert_eq!(SongConfig::from_toml(&saved).unwrap().to_toml().unwrap(), saved);
+    }
+
+    #[test]
+    fn test_cleared_preset_macro() {
+        let toml = r#"
+            [presets.wob]
+            macros.volume = {values = [10, 5]}
+            macros.arpeggio = {values = [0, 12]}
+
+            [channels.0]
+            preset = "wob"
+        "#;
+        let mut conf = SongConfig::from_toml(toml).unwrap();
+        conf.channels[0].macros.volume = None;
+        let saved = conf.to_toml().unwrap();
+        let loaded = SongConfig::from_toml(&saved).unwrap();
+        assert_eq!(loaded.channels[0].macros.volume, None);
+        assert_eq!(loaded.channels[0].macros.arpeggio, conf.channels[0].macros.arpeggio);
+        assert_eq!(loaded.channels[0].preset.as_deref(), Some("wob"));
+        assert_eq!(loaded.to_toml().unwrap(), saved);
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = std::env::temp_dir().join(format!("w4on2_test_includes_{}", std::process::id()));
+        fs::create_dir_all(dir.join("studio")).unwrap();
+        fs::write(
+            dir.join("studio/drums.toml"),
+            "[presets.kick]\npreset = \"kick\"\nvolume = 50\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("studio/all.toml"),
+            "include = [\"drums.toml\"]\n[presets.bass]\nvolume = 60\n",
+        )
+        .unwrap();
+        let toml =
+            "include = [\"studio/all.toml\"]\n[channels.0]\npreset = \"kick\"\n[channels.1]\npreset = \"bass\"\n";
+        let conf = SongConfig::from_toml_in(toml, &dir).unwrap();
+        assert_eq!(conf.channels[0].channel, Channel::Triangle);
+        assert_eq!(conf.channels[0].volume, 50);
+        assert_eq!(conf.channels[1].channel, SongTrackConfig::default().channel);
+        assert_eq!(conf.channels[1].volume, 60);
+        assert!(conf.presets.is_empty());
+        assert_eq!(
+            SongConfig::from_toml_in(&conf.to_toml().unwrap(), &dir)
+                .unwrap()
+                .channels[0]
+                .volume,
+            50
+        );
+
+        fs::write(dir.join("studio/bad.toml"), "[channels.0]\n").unwrap();
+        let err = SongConfig::from_toml_in("include = [\"studio/bad.toml\"]", &dir)
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("include `studio/bad.toml`: unknown key `channels`"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_automation() {
+        let toml = "[channels.2]\nvolume = 50\n\n[[automation]]\nbar = 33\nchannel = 2\n\n[automation.set.vibrato]\ndepth = 20\n";
+        let conf = SongConfig::from_toml(toml).unwrap();
+        assert_eq!(conf.automation[0].bar, Some(33));
+        let changed = conf.channels[2].with_changes(&conf.automation[0].set).unwrap();
+        assert_eq!(changed.vibrato.depth, 20);
+        assert_eq!(changed.volume, 50);
+        let saved = conf.to_toml().unwrap();
+        assert!(saved.contains("[[automation]]\nbar = 33\nchannel = 2\n\n[automation.set.vibrato]\ndepth = 20\n"));
+        assert_eq!(SongConfig::from_toml(&saved).unwrap().to_toml().unwrap(), saved);
+    }
+}
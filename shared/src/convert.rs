@@ -1,10 +1,227 @@
-use std::str::from_utf8;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    str::from_utf8,
+};
 
-use anyhow::{bail, Result};
 use log::*;
-use midly::{MetaMessage, MidiMessage, Smf, TrackEventKind};
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use serde::Serialize;
 
-use crate::*;
+use crate::{
+    crunch::{CrunchControl, Crunchable},
+    *,
+};
+
+// Where in the MIDI file something went wrong
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiLocation {
+    pub track: usize,                     // index in the MIDI file
+    pub tick: usize,                      // absolute MIDI tick
+    pub bar_beat: Option<(usize, usize)>, // 1-based, if the MIDI uses beat based timing
+    pub event: String,
+}
+impl Display for MidiLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MIDI track {}, tick {}", self.track, self.tick)?;
+        if let Some((bar, beat)) = self.bar_beat {
+            write!(f, " (bar {}:{})", bar, beat)?;
+        }
+        write!(f, ", event {}", self.event)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConvertError {
+    InvalidMidi(midly::Error),
+    MissingTiming(MidiLocation), // notes before the tempo or time signature
+    TempoChange(MidiLocation),
+    TimeSignatureChange(MidiLocation),
+    DuplicateTrackName(MidiLocation, String), // with the previous name
+    InvalidTrackName(MidiLocation),
+    ChannelTaken(MidiLocation, usize), // a track routed by name to a channel which has events from another track
+    Automation(usize, String),         // index in `SongConfig::automation`, and what's wrong with it
+    TooLarge(usize),                   // serialized size
+    OverBudget(usize, usize),          // smallest size, and the budget
+    Cancelled,
+}
+impl Display for ConvertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConvertError::InvalidMidi(err) => write!(f, "invalid MIDI file: {}", err),
+            ConvertError::MissingTiming(loc) => write!(f, "{}: tempo or time signature missing", loc),
+            ConvertError::TempoChange(loc) => write!(f, "{}: tempo changes are not supported", loc),
+            ConvertError::TimeSignatureChange(loc) => {
+                write!(f, "{}: time signature changes are not supported", loc)
+            }
+            ConvertError::DuplicateTrackName(loc, previous) => {
+                write!(f, "{}: track name already set (was {})", loc, previous)
+            }
+            ConvertError::InvalidTrackName(loc) => write!(f, "{}: track name is not valid UTF-8", loc),
+            ConvertError::ChannelTaken(loc, ch) => {
+                write!(f, "{}: channel {} already has events from another MIDI track", loc, ch)
+            }
+            ConvertError::Automation(i, msg) => write!(f, "automation #{}: {}", i + 1, msg),
+            ConvertError::TooLarge(size) => {
+                write!(
+                    f,
+                    "song is {} bytes, which is over the w4on2 limit of 65535 bytes",
+                    size
+                )
+            }
+            ConvertError::OverBudget(size, max_bytes) => write!(
+                f,
+                "song is {} bytes with all size strategies, {} bytes over the budget of {} bytes",
+                size,
+                size - max_bytes,
+                max_bytes
+            ),
+            ConvertError::Cancelled => write!(f, "conversion cancelled"),
+        }
+    }
+}
+impl std::error::Error for ConvertError {}
+
+// What a conversion ended up with, for showing to the user
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ConvertReport {
+    pub size: usize, // serialized
+    pub crunched: bool,
+    pub midi_bpm: f64,
+    pub bpm: f64, // after stretching
+    pub tick_wait: f64,
+    pub tracks: Vec<TrackReport>,
+    pub patterns: Vec<PatternReport>,
+    pub macro_bytes: usize,
+    pub ignored: Vec<IgnoredEvents>,
+    pub quantization: Vec<NoteQuantization>,
+    pub short_notes: Vec<ShortNote>,
+    pub size_strategies: Vec<SizeStrategy>, // from `convert_to_fit`
+}
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct TrackReport {
+    pub channel: usize,    // MIDI channel, or the channel matched by track name
+    pub events: usize,     // before crunching
+    pub bytes: usize,      // events before crunching
+    pub list_bytes: usize, // pattern list
+}
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PatternReport {
+    pub events: usize,
+    pub bytes: usize,
+    pub uses: usize,  // including repeats
+    pub saved: isize, // by crunching
+}
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct IgnoredEvents {
+    pub kind: String,
+    pub count: usize,
+}
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct NoteQuantization {
+    pub channel: usize, // MIDI channel
+    pub tick: usize,    // absolute MIDI tick
+    pub key: u8,
+    pub error: f64, // in WASM-4 ticks, positive if the note was moved later
+}
+// A note which ended before `QuantizeConfig::min_note_ticks` because the next note started
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ShortNote {
+    pub channel: usize, // MIDI channel
+    pub tick: usize,    // absolute MIDI tick
+    pub key: u8,
+    pub ticks: usize, // length in WASM-4 ticks, 0 if it was merged into the next note
+}
+impl Display for ConvertReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Size: {} bytes ({})",
+            self.size,
+            if self.crunched { "crunched" } else { "not crunched" }
+        )?;
+        writeln!(
+            f,
+            "Tempo: MIDI {:.2} BPM, WASM-4 {:.2} BPM, tick-wait {:.2}",
+            self.midi_bpm, self.bpm, self.tick_wait
+        )?;
+        writeln!(f, "Tracks:")?;
+        for t in &self.tracks {
+            writeln!(
+                f,
+                "  Channel {}: {} events, {} bytes, pattern list {} bytes",
+                t.channel, t.events, t.bytes, t.list_bytes
+            )?;
+        }
+        writeln!(f, "Patterns:")?;
+        for (i, p) in self.patterns.iter().enumerate() {
+            writeln!(
+                f,
+                "  {}: {} events, {} bytes, {} uses, {} bytes saved",
+                i, p.events, p.bytes, p.uses, p.saved
+            )?;
+        }
+        writeln!(f, "Macros: {} bytes", self.macro_bytes)?;
+        if !self.ignored.is_empty() {
+            writeln!(f, "Ignored MIDI events:")?;
+            for i in &self.ignored {
+                writeln!(f, "  {}: {}", i.kind, i.count)?;
+            }
+        }
+        if let Some(worst) = self
+            .quantization
+            .iter()
+            .max_by(|a, b| a.error.abs().total_cmp(&b.error.abs()))
+        {
+            let mean = self.quantization.iter().map(|n| n.error.abs()).sum::<f64>() / self.quantization.len() as f64;
+            writeln!(
+                f,
+                "Quantization: {} notes, mean error {:.3} ticks, largest {:.3} ticks (MIDI channel {}, tick {})",
+                self.quantization.len(),
+                mean,
+                worst.error,
+                worst.channel,
+                worst.tick
+            )?;
+        }
+        if !self.short_notes.is_empty() {
+            writeln!(f, "Short notes:")?;
+            for n in &self.short_notes {
+                write!(f, "  MIDI channel {}, tick {}, key {}: ", n.channel, n.tick, n.key)?;
+                if n.ticks == 0 {
+                    writeln!(f, "merged into the next note")?;
+                } else {
+                    writeln!(f, "shortened to {} ticks", n.ticks)?;
+                }
+            }
+        }
+        if !self.size_strategies.is_empty() {
+            let names: Vec<String> = self.size_strategies.iter().map(|s| s.to_string()).collect();
+            writeln!(f, "Size strategies: {}", names.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// Name to group ignored MIDI events by, e.g. "ProgramChange", "Controller 7" or "Meta Text"
+fn event_kind_name(kind: &TrackEventKind) -> String {
+    let variant = |debug: String| debug.split(['(', ' ', '{']).next().unwrap_or_default().to_owned();
+    match kind {
+        TrackEventKind::Midi {
+            message: MidiMessage::Controller { controller, .. },
+            ..
+        } => format!("Controller {}", controller),
+        TrackEventKind::Midi { message, .. } => variant(format!("{:?}", message)),
+        TrackEventKind::Meta(meta) => format!("Meta {}", variant(format!("{:?}", meta))),
+        TrackEventKind::SysEx(_) => "SysEx".to_owned(),
+        TrackEventKind::Escape(_) => "Escape".to_owned(),
+    }
+}
+
+// MIDI ticks per beat of the time signature, where the denominator is a power of 2 and quarter notes are 2
+fn midi_beat_ticks(ticks_per_beat: usize, timesig_denom: u8) -> usize {
+    ((ticks_per_beat * 4) >> timesig_denom.min(6)).max(1)
+}
 
 type MidlyTempo = midly::num::u24; // MetaMessage::Tempo
 type MidlyTimeSig = (u8, u8, u8, u8); // MetaMessage::TimeSignature
@@ -14,12 +231,26 @@ struct MidiTiming {
     timesig: Option<MidlyTimeSig>,
     result: Option<f64>,
     accumilated_inaccuracy: f64,
+    midi_bpm: f64,
+    bpm: f64,
+    tick_wait: f64,
     stretch: bool,
+    ticks_per_beat: Option<usize>, // MIDI ticks, from the header
+    beat_divisions: Option<usize>, // quantize to this part of a beat
+    quantize: QuantizeConfig,
+    beat_ticks: f64, // in WASM-4 ticks
+    grid: f64,       // in WASM-4 ticks
 }
 impl MidiTiming {
-    fn new(stretch: bool) -> Self {
+    fn new(stretch: bool, timing: Timing, beat_divisions: Option<usize>, quantize: &QuantizeConfig) -> Self {
         Self {
             stretch,
+            ticks_per_beat: match timing {
+                Timing::Metrical(ticks) => Some(ticks.as_int() as usize),
+                Timing::Timecode(..) => None,
+            },
+            beat_divisions,
+            quantize: quantize.clone(),
             ..Default::default()
         }
     }
@@ -32,7 +263,17 @@ impl MidiTiming {
             "MIDI BPM: {} | Optimal WASM-4 BPM: {} | Optimal WASM-4 tick-wait: {} | Optimal WASM-4 tick-divisor: {}",
             midi_bpm, opti_bpm, tick_wait, tick_divisor
         );
+        let beat_ticks = if self.stretch {
+            (tick_wait * timesig_num as usize) as f64
+        } else {
+            3600.0 / midi_bpm
+        };
+        self.beat_ticks = beat_ticks;
+        self.grid = self.beat_divisions.map_or(1.0, |d| beat_ticks / d as f64);
+        self.midi_bpm = midi_bpm;
         if self.stretch {
+            self.bpm = opti_bpm;
+            self.tick_wait = tick_wait as f64;
             self.result = Some(tick_divisor);
         } else {
             let tick_wait = 3600.0 / (midi_bpm * (timesig_num as f64));
@@ -42,163 +283,1007 @@ impl MidiTiming {
                 "Disregarding optimal, using: WASM-4 tick-wait: {} | WASM-4 tick-divisor: {}",
                 tick_wait, tick_divisor
             );
+            self.bpm = midi_bpm;
+            self.tick_wait = tick_wait;
             self.result = Some(tick_divisor);
         }
     }
-    fn get_w4_ticks(&mut self, midi_ticks: usize) -> Result<usize> {
+    // Unrounded (but swung), for measuring how far off the rounded ticks are
+    fn exact_w4_ticks(&self, midi_ticks: usize) -> Option<f64> {
         if let Some(tick_divisor) = &self.result {
-            let w4tick_f = (midi_ticks as f64) / tick_divisor;
-            self.accumilated_inaccuracy += (w4tick_f - w4tick_f.round()).abs();
-            Ok(w4tick_f.round() as usize)
+            Some(self.swing((midi_ticks as f64) / tick_divisor))
         } else if midi_ticks == 0 {
-            Ok(0)
+            Some(0.0)
         } else {
-            bail!("midi tempo or timesig missing");
+            None
+        }
+    }
+    // Moves the first half of each swing pair to take up `amount` percent of it, and the second half the rest
+    fn swing(&self, w4tick_f: f64) -> f64 {
+        let Some(swing) = &self.quantize.swing else {
+            return w4tick_f;
+        };
+        let half = self.beat_ticks / swing.divisions.max(1) as f64;
+        if half <= 0.0 {
+            return w4tick_f;
+        }
+        let split = 2.0 * half * swing.amount.clamp(0.0, 100.0) / 100.0;
+        let pair_start = (w4tick_f / (2.0 * half)).floor() * 2.0 * half;
+        let pos = w4tick_f - pair_start;
+        pair_start
+            + if pos < half {
+                pos * split / half
+            } else {
+                split + (pos - half) * (2.0 * half - split) / half
+            }
+    }
+    fn round_to_grid(&self, w4tick_f: f64) -> f64 {
+        let steps = w4tick_f / self.grid;
+        let steps = match self.quantize.mode {
+            // a little slack so exact ticks don't end up one step early because of float errors
+            QuantizeMode::Floor => (steps + 1e-6).floor(),
+            QuantizeMode::Nearest | QuantizeMode::PreserveDuration => steps.round(),
+        };
+        (steps * self.grid).round()
+    }
+    fn get_w4_ticks(&mut self, midi_ticks: usize) -> Option<usize> {
+        if self.result.is_none() {
+            return (midi_ticks == 0).then_some(0);
         }
+        let w4tick_f = self.exact_w4_ticks(midi_ticks)?;
+        let w4tick = self.round_to_grid(w4tick_f);
+        self.accumilated_inaccuracy += (w4tick_f - w4tick).abs();
+        Some(w4tick as usize)
     }
-    fn set_tempo(&mut self, tempo: MidlyTempo) -> Result<()> {
+    // The end of a note, which may keep the rounded length instead of rounding the end by itself
+    fn get_w4_end_ticks(&mut self, start: &HeldNote, midi_ticks: usize) -> Option<usize> {
+        if self.quantize.mode != QuantizeMode::PreserveDuration || self.result.is_none() {
+            return self.get_w4_ticks(midi_ticks);
+        }
+        let length = self.exact_w4_ticks(midi_ticks)? - start.exact;
+        Some(start.tick + self.round_to_grid(length) as usize)
+    }
+    // false if the tempo changed, which is (currently) not allowed
+    fn set_tempo(&mut self, tempo: MidlyTempo) -> bool {
         if let Some(cur_tempo) = &self.tempo {
             if *cur_tempo != tempo {
-                bail!("tempo changes (currently) not allowed");
+                return false;
             }
         }
         self.tempo = Some(tempo);
         if self.timesig.is_some() {
             self.calculate();
         }
-        Ok(())
+        true
     }
-    fn set_timesig(&mut self, timesig: MidlyTimeSig) -> Result<()> {
+    // false if the time signature changed, which is (currently) not allowed
+    fn set_timesig(&mut self, timesig: MidlyTimeSig) -> bool {
         if let Some(cur_timesig) = &self.timesig {
             if *cur_timesig != timesig {
-                bail!("time signature changes (currently) not allowed");
+                return false;
             }
         }
         self.timesig = Some(timesig);
         if self.tempo.is_some() {
             self.calculate();
         }
-        Ok(())
+        true
+    }
+    // 4/4 is assumed until there is a time signature
+    fn locate(&self, track: usize, tick: usize, event: &TrackEventKind) -> MidiLocation {
+        let bar_beat = self.ticks_per_beat.filter(|t| *t > 0).map(|ticks_per_beat| {
+            let (num, denom) = self.timesig.map_or((4, 2), |(num, denom, _, _)| (num.max(1), denom));
+            let beat_ticks = midi_beat_ticks(ticks_per_beat, denom);
+            let beat = tick / beat_ticks;
+            (beat / num as usize + 1, beat % num as usize + 1)
+        });
+        MidiLocation {
+            track,
+            tick,
+            bar_beat,
+            event: format!("{:?}", event),
+        }
+    }
+}
+
+// A note which may have been released, but isn't ended yet because of `QuantizeConfig::min_note_ticks`
+struct HeldNote {
+    tick: usize,
+    exact: f64, // for `QuantizeMode::PreserveDuration`
+    midi_tick: usize,
+    key: u8,
+    release: Option<(usize, Vec<TrackEvent>)>,
+}
+
+// Events of one MIDI channel, aligned to WASM-4 ticks
+#[derive(Default)]
+struct ChannelEvents {
+    events: Vec<TrackEvent>,
+    last_tick: usize,
+    held: Option<HeldNote>,
+}
+impl ChannelEvents {
+    fn push(&mut self, tick: usize, events: &mut Vec<TrackEvent>) {
+        if tick > self.last_tick {
+            self.events.push(TrackEvent::Delta(tick - self.last_tick));
+            self.last_tick = tick;
+        }
+        self.events.append(events);
+    }
+    // Writes the release of the held note if it's due by `tick`, or ends the note at `tick` anyway if `cut`
+    // Returns the note and how long it ended up being, if it was released.
+    fn end_note(&mut self, tick: usize, cut: bool) -> Option<(HeldNote, usize)> {
+        let due = matches!(&self.held.as_ref()?.release, Some((release_tick, _)) if *release_tick <= tick);
+        if !due && !cut {
+            return None;
+        }
+        let mut held = self.held.take()?;
+        let end = match held.release.take() {
+            Some((release_tick, mut events)) => {
+                let end = release_tick.min(tick);
+                self.push(end, &mut events);
+                end
+            }
+            None => return None, // overlapping notes slide or arpeggiate instead of ending
+        };
+        let length = end.saturating_sub(held.tick);
+        Some((held, length))
+    }
+}
+
+fn report_short_note(report: &mut ConvertReport, channel: usize, ended: Option<(HeldNote, usize)>, min_ticks: u8) {
+    if let Some((held, length)) = ended {
+        if length < (min_ticks as usize).max(1) {
+            report.short_notes.push(ShortNote {
+                channel,
+                tick: held.midi_tick,
+                key: held.key,
+                ticks: length,
+            });
+        }
+    }
+}
+
+// The automation of each channel at MIDI ticks, by index, with the whole config from then on
+type ScheduledAutomation = VecDeque<(usize, usize, SongTrackConfig)>;
+
+fn schedule_automation(def: &SongConfig, smf: &Smf) -> Result<[ScheduledAutomation; 16], ConvertError> {
+    let mut timesig = None;
+    let mut markers = vec![];
+    for midi_events in &smf.tracks {
+        let mut midi_ticks: usize = 0;
+        for event in midi_events {
+            midi_ticks += event.delta.as_int() as usize;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::TimeSignature(num, denom, _, _)) => {
+                    timesig.get_or_insert((num.max(1), denom));
+                }
+                TrackEventKind::Meta(MetaMessage::Marker(name) | MetaMessage::CuePoint(name)) => {
+                    markers.push((midi_ticks, String::from_utf8_lossy(name).trim().to_owned()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut scheduled: [Vec<(usize, usize)>; 16] = Default::default(); // MIDI tick, and index of the automation
+    for (i, auto) in def.automation.iter().enumerate() {
+        let err = |msg: &str| ConvertError::Automation(i, msg.to_owned());
+        let ticks = if let Some(bar) = auto.bar {
+            let Timing::Metrical(ticks_per_beat) = smf.header.timing else {
+                return Err(err("bars can't be used with timecode based MIDI timing"));
+            };
+            // 4/4 if there is no time signature, like `MidiTiming::locate`
+            let (num, denom) = timesig.unwrap_or((4, 2));
+            let beat_ticks = midi_beat_ticks(ticks_per_beat.as_int() as usize, denom);
+            let beats = ((bar - 1) * num as usize) as f64 + auto.beat.unwrap_or(1.0) - 1.0;
+            vec![(beats * beat_ticks as f64).round() as usize]
+        } else {
+            let marker = auto.marker.as_deref().unwrap_or_default().trim();
+            let ticks: Vec<usize> = markers
+                .iter()
+                .filter(|(_, name)| name.eq_ignore_ascii_case(marker))
+                .map(|(tick, _)| *tick)
+                .collect();
+            if ticks.is_empty() {
+                return Err(err(&format!("there is no MIDI marker named `{}`", marker)));
+            }
+            ticks
+        };
+        scheduled[auto.channel].extend(ticks.into_iter().map(|tick| (tick, i)));
+    }
+
+    let mut configs: [ScheduledAutomation; 16] = Default::default();
+    for (ch, mut scheduled) in scheduled.into_iter().enumerate() {
+        // in order of the file when they're at the same time
+        scheduled.sort();
+        let mut conf = def.channels[ch].clone();
+        for (tick, i) in scheduled {
+            conf = conf
+                .with_changes(&def.automation[i].set)
+                .map_err(|msg| ConvertError::Automation(i, msg))?;
+            configs[ch].push_back((tick, i, conf.clone()));
+        }
     }
+    Ok(configs)
 }
 
-fn midi_to_track_events(def: &SongConfig, smf: Smf, stretch: bool) -> Result<Vec<Vec<TrackEvent>>> {
+// Returns one track per MIDI channel, empty if the channel isn't used
+fn midi_to_track_events(
+    def: &SongConfig,
+    smf: Smf,
+    stretch: bool,
+    beat_divisions: Option<usize>,
+    report: &mut ConvertReport,
+) -> Result<(Vec<Vec<TrackEvent>>, Vec<MacroData>), ConvertError> {
     // Parse all MIDI events and convert into WASM-4 aligned "raw" events
-    let mut timing = MidiTiming::new(stretch);
-    let mut track_events: [Vec<TrackEvent>; 16] = Default::default();
-    let mut last_event_tick: [usize; 16] = Default::default();
+    let mut timing = MidiTiming::new(stretch, smf.header.timing, beat_divisions, &def.quantize);
+    let min_note_ticks = def.quantize.min_note_ticks;
+    let mut channels: [ChannelEvents; 16] = Default::default();
     let mut mapper = MidiEventMapper::new();
     let mut event_buffer = Vec::<TrackEvent>::new();
+    let mut ignored = BTreeMap::<String, usize>::new();
+    let mut ignore = |kind: String| *ignored.entry(kind).or_default() += 1;
     mapper.set_tracks(def.channels.clone()); // TODO: no clone
-    for midi_events in smf.tracks {
+    let mut automation = schedule_automation(def, &smf)?;
+    for (midi_track_i, midi_events) in smf.tracks.into_iter().enumerate() {
         let mut track_name: Option<String> = None;
+        let mut routed_channel: Option<u8> = None; // by track name, instead of the MIDI channel of each event
         let mut midi_ticks: usize = 0;
         for event in midi_events {
             midi_ticks += event.delta.as_int() as usize;
             match event.kind {
                 TrackEventKind::Midi { channel, message } => {
+                    let channel = match routed_channel {
+                        Some(routed) => routed,
+                        // channels matched by name only get events from their tracks
+                        None if def.channels[channel.as_int() as usize].matches_by_name() => {
+                            ignore(format!("Unmatched track on MIDI channel {}", channel));
+                            continue;
+                        }
+                        None => channel.as_int(),
+                    };
+                    // automation which is due goes before the event
+                    let ch = channel as usize;
+                    while automation[ch].front().is_some_and(|(tick, _, _)| *tick <= midi_ticks) {
+                        let (tick, i, conf) = automation[ch].pop_front().unwrap();
+                        let Some(ticks) = timing.get_w4_ticks(tick) else {
+                            let loc = timing.locate(midi_track_i, midi_ticks, &event.kind);
+                            return Err(ConvertError::MissingTiming(loc));
+                        };
+                        let ended = channels[ch].end_note(ticks, false);
+                        report_short_note(report, ch, ended, min_note_ticks);
+                        event_buffer.clear();
+                        mapper
+                            .automate(&mut event_buffer, channel, conf)
+                            .map_err(|msg| ConvertError::Automation(i, msg))?;
+                        channels[ch].push(ticks, &mut event_buffer);
+                    }
                     event_buffer.clear();
                     match message {
                         MidiMessage::NoteOn { key, vel } => {
-                            mapper.note_on(&mut event_buffer, channel.as_int(), key.as_int(), vel.as_int());
+                            mapper.note_on(&mut event_buffer, channel, key.as_int(), vel.as_int());
                         }
                         MidiMessage::NoteOff { key, .. } => {
-                            mapper.note_off(&mut event_buffer, channel.as_int(), key.as_int());
+                            mapper.note_off(&mut event_buffer, channel, key.as_int());
                         }
                         MidiMessage::Controller { controller, value } => {
                             // 10 is pan
                             if controller == 10 {
-                                mapper.pan(channel.as_int(), value.as_int());
+                                mapper.pan(channel, value.as_int());
+                            } else if controller == MIDI_CC_TREMOLO_DEPTH {
+                                mapper.tremolo_depth(&mut event_buffer, channel, value.as_int());
+                            } else if controller == MIDI_CC_TREMOLO_SPEED {
+                                mapper.tremolo_speed(&mut event_buffer, channel, value.as_int());
+                            } else if controller == MIDI_CC_SLIDE {
+                                mapper.slide(&mut event_buffer, channel, value.as_int());
+                            } else if controller == MIDI_CC_SLIDE_TICKS {
+                                mapper.slide_ticks(channel, value.as_int());
+                            } else {
+                                ignore(event_kind_name(&event.kind));
                             }
                         }
-                        _ => {}
+                        _ => ignore(event_kind_name(&event.kind)),
                     }
                     if !event_buffer.is_empty() {
-                        let ch = channel.as_int() as usize;
-                        let ticks = timing.get_w4_ticks(midi_ticks).unwrap();
-                        if ticks > last_event_tick[ch] {
-                            let delta = ticks - last_event_tick[ch];
-                            last_event_tick[ch] = ticks;
-                            track_events[ch].push(TrackEvent::Delta(delta));
+                        let channel_events = &mut channels[ch];
+                        let releases = event_buffer.contains(&TrackEvent::NotesOff);
+                        let ticks = match &channel_events.held {
+                            Some(held) if releases && held.release.is_none() => {
+                                timing.get_w4_end_ticks(held, midi_ticks)
+                            }
+                            _ => timing.get_w4_ticks(midi_ticks),
+                        };
+                        let Some(ticks) = ticks else {
+                            let loc = timing.locate(midi_track_i, midi_ticks, &event.kind);
+                            return Err(ConvertError::MissingTiming(loc));
+                        };
+                        // a new note always ends the previous one, even if it was shorter than the minimum
+                        let starts = matches!(message, MidiMessage::NoteOn { .. });
+                        let ended = channel_events.end_note(ticks, starts);
+                        report_short_note(report, ch, ended, min_note_ticks);
+                        if let MidiMessage::NoteOn { key, vel } = message {
+                            let exact = timing.exact_w4_ticks(midi_ticks).unwrap_or_default();
+                            if vel > 0 {
+                                report.quantization.push(NoteQuantization {
+                                    channel: ch,
+                                    tick: midi_ticks,
+                                    key: key.as_int(),
+                                    error: ticks as f64 - exact,
+                                });
+                            }
+                            channel_events.push(ticks, &mut event_buffer);
+                            channel_events.held = Some(HeldNote {
+                                tick: ticks,
+                                exact,
+                                midi_tick: midi_ticks,
+                                key: key.as_int(),
+                                release: None,
+                            });
+                        } else if let Some(held) =
+                            channel_events.held.as_mut().filter(|h| releases && h.release.is_none())
+                        {
+                            let release_tick = ticks.max(held.tick + min_note_ticks as usize);
+                            held.release = Some((release_tick, std::mem::take(&mut event_buffer)));
+                        } else {
+                            channel_events.push(ticks, &mut event_buffer);
                         }
-                        track_events[ch].append(&mut event_buffer);
                     }
                 }
                 TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
-                    timing.set_tempo(tempo).unwrap();
+                    if !timing.set_tempo(tempo) {
+                        let loc = timing.locate(midi_track_i, midi_ticks, &event.kind);
+                        return Err(ConvertError::TempoChange(loc));
+                    }
                 }
                 TrackEventKind::Meta(MetaMessage::TimeSignature(a, b, c, d)) => {
-                    timing.set_timesig((a, b, c, d)).unwrap();
+                    if !timing.set_timesig((a, b, c, d)) {
+                        let loc = timing.locate(midi_track_i, midi_ticks, &event.kind);
+                        return Err(ConvertError::TimeSignatureChange(loc));
+                    }
                 }
                 TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                    let loc = || timing.locate(midi_track_i, midi_ticks, &event.kind);
                     if let Some(current) = track_name {
-                        panic!("midi track name already set (was {current})");
+                        return Err(ConvertError::DuplicateTrackName(loc(), current));
                     }
-                    track_name = Some(from_utf8(name).expect("invalid track name utf8").to_owned());
+                    let Ok(name) = from_utf8(name) else {
+                        return Err(ConvertError::InvalidTrackName(loc()));
+                    };
+                    // events can only be added in order, so tracks with the same name take one entry each
+                    let matching: Vec<usize> = (0..def.channels.len())
+                        .filter(|ch| def.channels[*ch].matches_track_name(name))
+                        .collect();
+                    if let Some(first) = matching.first() {
+                        let Some(ch) = matching.iter().find(|ch| channels[**ch].events.is_empty()) else {
+                            return Err(ConvertError::ChannelTaken(loc(), *first));
+                        };
+                        info!("MIDI track {} ({}) goes to channel {}", midi_track_i, name, ch);
+                        routed_channel = Some(*ch as u8);
+                    }
+                    track_name = Some(name.to_owned());
                 }
                 TrackEventKind::Meta(MetaMessage::EndOfTrack) => {}
                 _ => {
-                    warn!("Unhandled MIDI event: {:?}", event.kind);
+                    debug!("Unhandled MIDI event: {:?}", event.kind);
+                    ignore(event_kind_name(&event.kind));
                 }
             }
         }
     }
+    for (ch, channel_events) in channels.iter_mut().enumerate() {
+        let ended = channel_events.end_note(usize::MAX, true);
+        report_short_note(report, ch, ended, min_note_ticks);
+    }
     info!("Inaccuracy: {}", timing.accumilated_inaccuracy,);
-    Ok(track_events.into_iter().filter(|t| !t.is_empty()).collect())
+    report.midi_bpm = timing.midi_bpm;
+    report.bpm = timing.bpm;
+    report.tick_wait = timing.tick_wait;
+    report.ignored = ignored
+        .into_iter()
+        .map(|(kind, count)| IgnoredEvents { kind, count })
+        .collect();
+    Ok((
+        channels.into_iter().map(|c| c.events).collect(),
+        mapper.macros().to_vec(),
+    ))
 }
-const PATTERN_CREATE_COST: usize = 3; // cost of using a pattern (u8) + pattern length (u16)
+const PATTERN_CREATE_COST: usize = 2; // pattern offset (u16), references are sized by `PatternRef`
 
-fn collapse_tracks(tracks: Vec<Vec<TrackEvent>>) -> Vec<Vec<TrackEvent>> {
+// Round velocities to the middle of `step` sized ranges, so more of them end up equal
+fn merge_velocities(tracks: Vec<Vec<TrackEvent>>, step: u8) -> Vec<Vec<TrackEvent>> {
     tracks
         .into_iter()
-        .filter(|t| !t.is_empty())
         .map(|t| {
-            let mut new_track = Vec::<TrackEvent>::with_capacity(t.len());
-            for e in t {
-                if let TrackEvent::NotesOff = e {
-                    if let Some(pe) = new_track.last_mut() {
-                        if let TrackEvent::Delta(d) = pe {
-                            *pe = TrackEvent::DeltaNotesOff(*d);
-                            continue;
-                        }
+            t.into_iter()
+                .map(|e| match e {
+                    TrackEvent::SetVelocity(v) => {
+                        TrackEvent::SetVelocity((v / step * step + step / 2).min(W4ON2_VELOCITY_MAX as u8))
                     }
-                }
-                new_track.push(e);
-            }
-            new_track
+                    e => e,
+                })
+                .collect()
         })
         .collect()
 }
 
-pub fn convert(conf: &SongConfig, midi_bytes: &[u8], stretch: bool, crunch: bool) -> Result<Vec<u8>> {
-    let smf = Smf::parse(midi_bytes)?;
+// Progressively more aggressive ways of making a song smaller, in the order `convert_to_fit` tries them
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum SizeStrategy {
+    Crunch,
+    DropRedundantSets,
+    Quantize(usize),     // to this part of a beat
+    MergeVelocities(u8), // into ranges of this size
+}
+pub const SIZE_STRATEGIES: [SizeStrategy; 6] = [
+    SizeStrategy::Crunch,
+    SizeStrategy::DropRedundantSets,
+    SizeStrategy::Quantize(4),
+    SizeStrategy::Quantize(2),
+    SizeStrategy::MergeVelocities(16),
+    SizeStrategy::MergeVelocities(32),
+];
+impl Display for SizeStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SizeStrategy::Crunch => write!(f, "crunch"),
+            SizeStrategy::DropRedundantSets => write!(f, "drop redundant Set* events"),
+            SizeStrategy::Quantize(divisions) => write!(f, "quantize to 1/{} beats", divisions),
+            SizeStrategy::MergeVelocities(step) => write!(f, "merge velocities in steps of {}", step),
+        }
+    }
+}
+
+// Strategies applied on top of each other
+#[derive(Default)]
+struct Reductions {
+    drop_redundant_sets: bool,
+    beat_divisions: Option<usize>,
+    velocity_step: Option<u8>,
+}
+
+fn build_song(
+    conf: &SongConfig,
+    midi_bytes: &[u8],
+    stretch: bool,
+    crunch: Option<&mut CrunchControl>,
+    reductions: &Reductions,
+    report: &mut ConvertReport,
+) -> Result<W4PlayerSong, ConvertError> {
+    let smf = Smf::parse(midi_bytes).map_err(ConvertError::InvalidMidi)?;
 
     // Now that everything is loaded, here are the general steps:
     // - Convert all MIDI events into WASM-4 tick-aligned events w4on2 track events
-    // - Collapse into convert unique optimizations (e.g. DeltaNotesOff)
+    // - Optimize events without changing the sound (e.g. DeltaNotesOff, dropping ineffective Set* events)
     // - Crunch everything into patterns
     // - Serialize into binary data
 
     // Convert
-    let tracks = midi_to_track_events(conf, smf, stretch)?;
-    // Collapse
-    let tracks = collapse_tracks(tracks);
-    // Crunch/create song
-    let song = if crunch {
-        debug!("Crunching...");
-        let (dict, usages) = crunch::crunch(tracks.clone(), W4ON2_MAX_PATTERNS as usize, PATTERN_CREATE_COST);
-        assert_eq!(crunch::uncrunch(&dict, &usages), tracks);
-        W4PlayerSong {
-            patterns: dict,
-            tracks: usages,
-        }
-    } else {
-        W4PlayerSong {
-            tracks: (0..tracks.len()).map(|i| vec![i]).collect(),
-            patterns: tracks,
-        }
+    let (mut tracks, macros) = midi_to_track_events(conf, smf, stretch, reductions.beat_divisions, report)?;
+    if let Some(step) = reductions.velocity_step {
+        tracks = merge_velocities(tracks, step);
+    }
+    if reductions.drop_redundant_sets {
+        tracks = tracks.iter().map(|t| optimize::drop_redundant_sets(t)).collect();
+    }
+    // Optimize, keeping track of which MIDI channel each track came from
+    let (channels, tracks): (Vec<usize>, Vec<Vec<TrackEvent>>) = tracks
+        .iter()
+        .map(|t| optimize::optimize_track(t))
+        .enumerate()
+        .filter(|(_, t)| !t.is_empty())
+        .unzip();
+    report.tracks = channels
+        .into_iter()
+        .zip(&tracks)
+        .map(|(channel, t)| TrackReport {
+            channel,
+            events: t.len(),
+            bytes: t.iter().map(Crunchable::size).sum(),
+            list_bytes: 0,
+        })
+        .collect();
+    // Create song
+    let uncrunched = W4PlayerSong {
+        tracks: (0..tracks.len()).map(|i| vec![PatternRef::new(i)]).collect(),
+        patterns: tracks.clone(),
+        macros: macros.clone(),
+    };
+    let Some(control) = crunch else {
+        finish_report(report, &uncrunched, None);
+        return Ok(uncrunched);
+    };
+
+    // Crunch
+    debug!("Crunching...");
+    let crunched = crunch::crunch_transposed(
+        tracks.clone(),
+        W4ON2_MAX_PATTERNS as usize,
+        PATTERN_CREATE_COST,
+        control,
+    );
+    if crunched.cancelled {
+        return Err(ConvertError::Cancelled);
+    }
+    if crunched.out_of_budget {
+        info!("Crunch budget ran out, using the best result so far");
+    }
+    assert_eq!(crunch::uncrunch_transposed(&crunched.dict, &crunched.usages), tracks);
+    let song = W4PlayerSong {
+        patterns: crunched.dict,
+        tracks: crunched.usages,
+        macros,
     };
 
     // Output
+    info!("Crunched {} bytes into {} bytes", uncrunched.size(), song.size());
+    if song.size() >= uncrunched.size() {
+        warn!("Crunching didn't save any space, using the uncrunched song");
+        finish_report(report, &uncrunched, None);
+        Ok(uncrunched)
+    } else {
+        finish_report(report, &song, Some(&crunched.saved));
+        Ok(song)
+    }
+}
+
+// Fill in the parts of the report which depend on the final song
+fn finish_report(report: &mut ConvertReport, song: &W4PlayerSong, saved: Option<&[isize]>) {
+    report.size = song.size();
+    report.crunched = saved.is_some();
+    for (t, list) in report.tracks.iter_mut().zip(&song.tracks) {
+        t.list_bytes = list.iter().map(PatternRef::size).sum();
+    }
+    report.patterns = song
+        .patterns
+        .iter()
+        .enumerate()
+        .map(|(pattern_i, p)| PatternReport {
+            events: p.len(),
+            bytes: p.iter().map(Crunchable::size).sum(),
+            uses: song
+                .tracks
+                .iter()
+                .flatten()
+                .filter(|r| r.pattern_i == pattern_i)
+                .map(|r| r.repeat as usize + 1)
+                .sum(),
+            saved: saved.and_then(|s| s.get(pattern_i)).copied().unwrap_or_default(),
+        })
+        .collect();
+    let mut macro_buf = vec![];
+    for m in &song.macros {
+        m.serialize_into(&mut macro_buf);
+    }
+    report.macro_bytes = macro_buf.len();
+    for (pattern_i, p) in report.patterns.iter().enumerate().filter(|(_, p)| p.saved != 0) {
+        debug!(
+            "Pattern {}: {} events, {} uses, {} bytes saved",
+            pattern_i, p.events, p.uses, p.saved
+        );
+    }
+}
+
+fn serialize_checked(song: &W4PlayerSong) -> Result<Vec<u8>, ConvertError> {
+    let size = song.size();
+    if size > 0xffff {
+        return Err(ConvertError::TooLarge(size));
+    }
     Ok(song.serialize())
 }
+
+pub fn convert(
+    conf: &SongConfig,
+    midi_bytes: &[u8],
+    stretch: bool,
+    crunch: bool,
+) -> Result<(Vec<u8>, ConvertReport), ConvertError> {
+    convert_with(conf, midi_bytes, stretch, crunch.then(CrunchControl::default))
+}
+
+// Like `convert`, but with progress, cancellation and budgets for crunching - `None` doesn't crunch at all
+pub fn convert_with(
+    conf: &SongConfig,
+    midi_bytes: &[u8],
+    stretch: bool,
+    mut crunch: Option<CrunchControl>,
+) -> Result<(Vec<u8>, ConvertReport), ConvertError> {
+    let mut report = ConvertReport::default();
+    let song = build_song(
+        conf,
+        midi_bytes,
+        stretch,
+        crunch.as_mut(),
+        &Reductions::default(),
+        &mut report,
+    )?;
+    Ok((serialize_checked(&song)?, report))
+}
+
+// Like `convert_with`, but applies `SIZE_STRATEGIES` one by one until the song is at most `max_bytes`
+// The report lists the strategies that were needed, or the error says how far over budget the smallest attempt was.
+pub fn convert_to_fit(
+    conf: &SongConfig,
+    midi_bytes: &[u8],
+    stretch: bool,
+    crunch: Option<CrunchControl>,
+    max_bytes: usize,
+) -> Result<(Vec<u8>, ConvertReport), ConvertError> {
+    let max_bytes = max_bytes.min(0xffff);
+    let crunching = crunch.is_some();
+    let mut control = crunch.unwrap_or_default();
+    let mut reductions = Reductions::default();
+    let mut applied: Vec<SizeStrategy> = vec![];
+    let mut smallest = usize::MAX;
+    for next in std::iter::once(None).chain(SIZE_STRATEGIES.into_iter().map(Some)) {
+        match next {
+            None => {}
+            Some(SizeStrategy::Crunch) if crunching => continue, // already tried
+            Some(SizeStrategy::Crunch) => {}
+            Some(SizeStrategy::DropRedundantSets) => reductions.drop_redundant_sets = true,
+            Some(SizeStrategy::Quantize(divisions)) => reductions.beat_divisions = Some(divisions),
+            Some(SizeStrategy::MergeVelocities(step)) => reductions.velocity_step = Some(step),
+        }
+        // coarser steps of the same strategy replace the previous ones
+        applied.retain(|s| next.is_none_or(|n| std::mem::discriminant(s) != std::mem::discriminant(&n)));
+        applied.extend(next);
+        let crunch = (crunching || !applied.is_empty()).then_some(&mut control);
+        let mut report = ConvertReport::default();
+        let song = build_song(conf, midi_bytes, stretch, crunch, &reductions, &mut report)?;
+        let size = song.size();
+        if size <= max_bytes {
+            report.size_strategies = applied;
+            return Ok((song.serialize(), report));
+        }
+        info!("{} bytes is over the budget of {} bytes", size, max_bytes);
+        smallest = smallest.min(size);
+    }
+    Err(ConvertError::OverBudget(smallest, max_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::{Format, Header, TrackEvent as MidiEvent};
+
+    // A MIDI file at 120 BPM in 4/4 with 96 ticks per beat, with a tempo track before `tracks`
+    fn midi_bytes(tracks: Vec<Vec<MidiEvent>>) -> Vec<u8> {
+        let meta = |kind| MidiEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(kind),
+        };
+        let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(96.into())));
+        smf.tracks.push(vec![
+            meta(MetaMessage::Tempo(500000.into())),
+            meta(MetaMessage::TimeSignature(4, 2, 24, 8)),
+        ]);
+        smf.tracks.extend(tracks);
+        let mut bytes = vec![];
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    // A beat long note for each key, in a track with `name` unless it's empty
+    fn notes<'a>(name: &'a str, channel: u8, keys: &[u8]) -> Vec<MidiEvent<'a>> {
+        let midi = |delta: u32, message| MidiEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Midi {
+                channel: channel.into(),
+                message,
+            },
+        };
+        let mut events = vec![];
+        if !name.is_empty() {
+            events.push(MidiEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+            });
+        }
+        for key in keys {
+            events.push(midi(
+                0,
+                MidiMessage::NoteOn {
+                    key: (*key).into(),
+                    vel: 100.into(),
+                },
+            ));
+            events.push(midi(
+                96,
+                MidiMessage::NoteOff {
+                    key: (*key).into(),
+                    vel: 0.into(),
+                },
+            ));
+        }
+        events
+    }
+
+    // 96 MIDI ticks per beat at 120 BPM in 4/4, which stretches to 32 WASM-4 ticks per beat
+    fn timing(quantize: QuantizeConfig) -> MidiTiming {
+        let mut timing = MidiTiming::new(true, Timing::Metrical(96.into()), None, &quantize);
+        assert!(timing.set_tempo(500000.into()));
+        assert!(timing.set_timesig((4, 2, 24, 8)));
+        timing
+    }
+
+    #[test]
+    fn test_quantize() {
+        let mut nearest = timing(QuantizeConfig::default());
+        assert_eq!(nearest.get_w4_ticks(96), Some(32));
+        assert_eq!(nearest.get_w4_ticks(4), Some(1));
+        assert_eq!(nearest.get_w4_ticks(5), Some(2));
+
+        let mut floor = timing(QuantizeConfig {
+            mode: QuantizeMode::Floor,
+            ..Default::default()
+        });
+        assert_eq!(floor.get_w4_ticks(5), Some(1));
+        assert_eq!(floor.get_w4_ticks(96), Some(32));
+
+        // the end is rounded by itself, or keeps the rounded length
+        let held = HeldNote {
+            tick: 1,
+            exact: 4.0 / 3.0,
+            midi_tick: 4,
+            key: 60,
+            release: None,
+        };
+        assert_eq!(nearest.get_w4_end_ticks(&held, 8), Some(3));
+        let mut preserve = timing(QuantizeConfig {
+            mode: QuantizeMode::PreserveDuration,
+            ..Default::default()
+        });
+        assert_eq!(preserve.get_w4_end_ticks(&held, 8), Some(2));
+
+        // eighths, with the second one of each pair three quarters in
+        let mut swing = timing(QuantizeConfig {
+            swing: Some(Swing {
+                divisions: 2,
+                amount: 75.0,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(swing.get_w4_ticks(0), Some(0));
+        assert_eq!(swing.get_w4_ticks(48), Some(24));
+        assert_eq!(swing.get_w4_ticks(96), Some(32));
+        assert_eq!(swing.get_w4_ticks(144), Some(56));
+    }
+
+    #[test]
+    fn test_min_note_length() {
+        let mut channel = ChannelEvents::default();
+        let note = |tick, release| HeldNote {
+            tick,
+            exact: tick as f64,
+            midi_tick: 0,
+            key: 60,
+            release,
+        };
+        // released on the same tick, but held until the next tick
+        channel.push(0, &mut vec![TrackEvent::NoteOn(60)]);
+        channel.held = Some(note(0, Some((1, vec![TrackEvent::NotesOff]))));
+        assert!(channel.end_note(0, false).is_none());
+        let (_, length) = channel.end_note(1, false).unwrap();
+        assert_eq!(length, 1);
+        // a new note on the same tick cuts it
+        channel.push(4, &mut vec![TrackEvent::NoteOn(60)]);
+        channel.held = Some(note(4, Some((5, vec![TrackEvent::NotesOff]))));
+        let (_, length) = channel.end_note(4, true).unwrap();
+        assert_eq!(length, 0);
+        // overlapping notes don't end
+        channel.held = Some(note(4, None));
+        assert!(channel.end_note(4, true).is_none());
+        assert_eq!(
+            channel.events,
+            vec![
+                TrackEvent::NoteOn(60),
+                TrackEvent::Delta(1),
+                TrackEvent::NotesOff,
+                TrackEvent::Delta(3),
+                TrackEvent::NoteOn(60),
+                TrackEvent::NotesOff,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_schedule_automation() {
+        let meta = |delta: u32, kind| MidiEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Meta(kind),
+        };
+        let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(96.into())));
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::TimeSignature(3, 2, 24, 8)),
+            meta(96 * 6, MetaMessage::Marker(b"Chorus ")),
+            meta(96 * 3, MetaMessage::CuePoint(b"chorus")),
+        ]);
+        let conf = SongConfig::from_toml(
+            r#"
+            [[automation]]
+            marker = "Chorus"
+            channel = 2
+            set = {volume = 100}
+            [[automation]]
+            bar = 3
+            beat = 1.5
+            channel = 2
+            set = {vibrato = {depth = 20}}
+            "#,
+        )
+        .unwrap();
+        let scheduled = schedule_automation(&conf, &smf).unwrap();
+        let ticks: Vec<(usize, usize)> = scheduled[2].iter().map(|(tick, i, _)| (*tick, *i)).collect();
+        assert_eq!(ticks, [(96 * 6, 0), (96 * 6 + 48, 1), (96 * 9, 0)]);
+        // each change keeps the earlier ones
+        assert_eq!(scheduled[2][1].2.volume, 100);
+        assert_eq!(scheduled[2][1].2.vibrato.depth, 20);
+        assert!(scheduled[0].is_empty());
+
+        let conf = SongConfig::from_toml("[[automation]]\nmarker = \"Bridge\"\nchannel = 0\nset = {}").unwrap();
+        assert_eq!(
+            schedule_automation(&conf, &smf).err().unwrap().to_string(),
+            "automation #1: there is no MIDI marker named `Bridge`"
+        );
+    }
+
+    #[test]
+    fn test_too_many_automated_macros() {
+        let mut toml = String::new();
+        for i in 0..300 {
+            toml += &format!(
+                "[[automation]]\nbar = 1\nchannel = 0\nset = {{macros.volume = {{values = [{}, {}]}}}}\n",
+                i / 16,
+                i % 16
+            );
+        }
+        let conf = SongConfig::from_toml(&toml).unwrap();
+        let midi = midi_bytes(vec![notes("", 0, &[60])]);
+        let err = convert(&conf, &midi, true, false).err().unwrap();
+        // the first 255 fit, the error is about the next one
+        assert_eq!(
+            err.to_string(),
+            format!("automation #256: more than {} distinct macros", W4ON2_MAX_MACROS)
+        );
+    }
+
+    #[test]
+    fn test_tremolo_ccs() {
+        let cc = |controller: u8, value: u8| MidiEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Midi {
+                channel: 0.into(),
+                message: MidiMessage::Controller {
+                    controller: controller.into(),
+                    value: value.into(),
+                },
+            },
+        };
+        let mut track = vec![cc(MIDI_CC_TREMOLO_SPEED, 127), cc(MIDI_CC_TREMOLO_DEPTH, 64)];
+        track.extend(notes("", 0, &[60]));
+        let midi = midi_bytes(vec![track]);
+        let conf = SongConfig::from_toml("[channels.0]\n").unwrap();
+        let mut report = ConvertReport::default();
+        let smf = Smf::parse(&midi).unwrap();
+        let (tracks, _) = midi_to_track_events(&conf, smf, false, None, &mut report).unwrap();
+        let tremolo: Vec<&TrackEvent> = tracks[0]
+            .iter()
+            .filter(|e| matches!(e, TrackEvent::SetTremolo(_)))
+            .collect();
+        assert_eq!(
+            tremolo.last(),
+            Some(&&TrackEvent::SetTremolo(Tremolo { speed: 255, depth: 128 }))
+        );
+        assert!(report.ignored.is_empty());
+    }
+
+    #[test]
+    fn test_route_by_track_name() {
+        let conf = SongConfig::from_toml(
+            r#"
+            [channels.0]
+            midi_track = "Lead"
+            [channels.1]
+            midi_track = "bass "
+            [channels.2]
+            nickname = "Pad"
+            match_nickname = true
+            "#,
+        )
+        .unwrap();
+        let midi = midi_bytes(vec![
+            notes("Lead", 0, &[60, 62]),
+            notes("Bass", 0, &[40]),
+            notes("Drums", 0, &[36]),
+            notes("PAD", 5, &[50, 52, 55]),
+        ]);
+        let (_, report) = convert(&conf, &midi, false, false).unwrap();
+        let tracks: Vec<(usize, usize)> = report.tracks.iter().map(|t| (t.channel, t.events)).collect();
+        assert_eq!(tracks.iter().map(|(ch, _)| *ch).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(tracks[2].1 > tracks[0].1 && tracks[0].1 > tracks[1].1);
+        let unmatched = report
+            .ignored
+            .iter()
+            .find(|i| i.kind == "Unmatched track on MIDI channel 0");
+        assert_eq!(unmatched.unwrap().count, 2);
+
+        // a second track for the same channel
+        let midi = midi_bytes(vec![notes("Lead", 0, &[60]), notes("lead", 1, &[62])]);
+        let err = convert(&conf, &midi, false, false).err().unwrap().to_string();
+        assert!(err.starts_with("MIDI track 2, tick 0"));
+        assert!(err.ends_with(": channel 0 already has events from another MIDI track"));
+
+        // entries with the same name take one track each, in order
+        let conf = SongConfig::from_toml("[channels.0]\nmidi_track = \"Lead\"\n[channels.3]\nmidi_track = \"lead\"\n");
+        let (_, report) = convert(&conf.unwrap(), &midi, false, false).unwrap();
+        assert_eq!(report.tracks.iter().map(|t| t.channel).collect::<Vec<_>>(), [0, 3]);
+    }
+
+    #[test]
+    fn test_report() {
+        let conf = SongConfig::from_toml("[channels.0]\nmacros.volume = {values = [10, 5]}\n").unwrap();
+        let mut lead = notes("Lead", 0, &[60, 62, 60, 62, 60, 62]);
+        for value in [100, 90] {
+            lead.insert(
+                1,
+                MidiEvent {
+                    delta: 0.into(),
+                    kind: TrackEventKind::Midi {
+                        channel: 0.into(),
+                        message: MidiMessage::Controller {
+                            controller: 7.into(),
+                            value: value.into(),
+                        },
+                    },
+                },
+            );
+        }
+        let midi = midi_bytes(vec![lead, notes("", 1, &[40, 40])]);
+        let (serialized, report) = convert(&conf, &midi, true, true).unwrap();
+        assert!(report.crunched);
+        assert_eq!(report.size, serialized.len());
+        // the rest is the header and offsets, see `W4PlayerSong::serialize`
+        let header = 5 + 2 * (report.patterns.len() + report.tracks.len() + 1);
+        let tracks: usize = report.tracks.iter().map(|t| t.list_bytes).sum();
+        let patterns: usize = report.patterns.iter().map(|p| p.bytes).sum();
+        assert_eq!(header + tracks + patterns + report.macro_bytes, report.size);
+        assert_eq!(report.macro_bytes, 3 + 2);
+
+        let controllers = report.ignored.iter().find(|i| i.kind == "Controller 7");
+        assert_eq!(controllers.unwrap().count, 2);
+        assert_eq!(report.quantization.len(), 8);
+        assert!(report.quantization.iter().all(|q| q.error == 0.0));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["size"], report.size);
+        assert_eq!(json["ignored"][0]["kind"], "Controller 7");
+    }
+
+    #[test]
+    fn test_render() {
+        let conf = SongConfig::from_toml("[channels.0]\n[channels.1]\n").unwrap();
+        let melody = [60, 62, 64, 65].repeat(8);
+        let midi = midi_bytes(vec![notes("", 0, &melody), notes("", 1, &[40, 40])]);
+        let options = crate::RenderOptions::default();
+        let (mix, report) = crate::render(&conf, &midi, &options).unwrap();
+        assert!(report.crunched);
+        // the same size and sound as converting and bouncing
+        let (serialized, _) = convert(&conf, &midi, true, true).unwrap();
+        assert_eq!(report.size, serialized.len());
+        assert_eq!(
+            mix.samples,
+            crate::bounce::bounce(&serialized, &options.bounce).unwrap().samples
+        );
+        assert!(mix.peak > 0.0);
+
+        // crunching only changes the size
+        let uncrunched = crate::RenderOptions {
+            crunch: false,
+            ..options
+        };
+        let (uncrunched_mix, uncrunched_report) = crate::render(&conf, &midi, &uncrunched).unwrap();
+        assert!(!uncrunched_report.crunched);
+        assert!(uncrunched_report.size > report.size);
+        assert_eq!(uncrunched_mix.samples, mix.samples);
+    }
+}
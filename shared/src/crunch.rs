@@ -1,138 +1,392 @@
 use std::{
     cmp::min,
-    collections::{HashMap, HashSet},
+    collections::BTreeMap,
     fmt::Debug,
-    hash::Hash,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+use crate::PatternRef;
+
+// How two elements relate when looking for matches
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Relation {
+    Different,
+    Equal,          // equal no matter the transposition
+    Transposed(i8), // equal when transposed by some semitones
+}
+
+// Elements which can be crunched into patterns
+// Transposable elements (which are `Transposed(0)` to themselves) should relate to each other by their difference.
+pub trait Crunchable: Clone + Debug {
+    fn relation(&self, other: &Self) -> Relation;
+    fn transposed(&self, semitones: i8) -> Self;
+    fn size(&self) -> usize; // serialized size in bytes
+}
+
+// Plain elements only match if equal, and are counted as a byte each
+#[derive(Clone, Debug, PartialEq)]
+struct Plain<T>(T);
+impl<T: PartialEq + Clone + Debug> Crunchable for Plain<T> {
+    fn relation(&self, other: &Self) -> Relation {
+        if self.0 == other.0 {
+            Relation::Equal
+        } else {
+            Relation::Different
+        }
+    }
+    fn transposed(&self, _semitones: i8) -> Self {
+        self.clone()
+    }
+    fn size(&self) -> usize {
+        1
+    }
+}
+
+#[derive(Clone, Debug)]
 struct TrackChunk<T> {
     track_i: usize,
     src_i: usize,
-    chunk: Vec<T>, // TODO: slice
+    chunk: Vec<T>,
+    classes: Vec<usize>, // index into `Classes` for each element
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
 struct PatternMatch {
     chunk_i: usize,
     start: usize,
     length: usize,
+    transpose: i8, // relative to the first match
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
 struct TrackPattern {
     src_i: usize,
     pattern_i: usize,
+    transpose: i8,
+}
+
+// Groups of equal elements, so they can be compared as integers
+struct Classes<T> {
+    representatives: Vec<T>,
+    transposable: Vec<bool>,
+}
+impl<T: Crunchable> Classes<T> {
+    fn class_of(&mut self, e: &T) -> usize {
+        let found = self
+            .representatives
+            .iter()
+            .position(|r| matches!(r.relation(e), Relation::Equal | Relation::Transposed(0)));
+        found.unwrap_or_else(|| {
+            self.transposable.push(e.relation(e) == Relation::Transposed(0));
+            self.representatives.push(e.clone());
+            self.representatives.len() - 1
+        })
+    }
+}
+
+// What the suffix array is built from
+// Transposable elements are stored as the interval from the previous one, so transposed runs look equal.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+enum Token {
+    Class(usize),
+    FirstNote,
+    Interval(i8),
+    Separator(usize), // unique per chunk so that nothing matches across chunks
 }
 
-// Very naive algorithm to find matching subsections within all chunks
-// Has risk of exploding as the amount of chunks grows, but hopefully it will stay usable
-fn crunchy_chunky<T: PartialEq + Debug + Clone>(
+// Suffix array by prefix doubling with counting sorts, `s` being dense ranks
+fn suffix_array(s: &[usize]) -> Vec<usize> {
+    let n = s.len();
+    let mut sa: Vec<usize> = (0..n).collect();
+    if n < 2 {
+        return sa;
+    }
+    let mut rank = s.to_vec();
+    let mut next_rank = vec![0; n];
+    let mut by_second = vec![0; n];
+    sa.sort_by_key(|&i| rank[i]);
+    let mut k = 1;
+    loop {
+        // order by the second half first: suffixes without one come first, the rest follow the current order
+        let mut pos = 0;
+        for i in n.saturating_sub(k)..n {
+            by_second[pos] = i;
+            pos += 1;
+        }
+        for &i in &sa {
+            if i >= k {
+                by_second[pos] = i - k;
+                pos += 1;
+            }
+        }
+        // then a stable counting sort by the first half
+        let mut starts = vec![0; n.max(*rank.iter().max().unwrap() + 1) + 1];
+        for &r in &rank {
+            starts[r + 1] += 1;
+        }
+        for r in 1..starts.len() {
+            starts[r] += starts[r - 1];
+        }
+        for &i in &by_second {
+            sa[starts[rank[i]]] = i;
+            starts[rank[i]] += 1;
+        }
+        // re-rank
+        let second = |i: usize| if i + k < n { Some(rank[i + k]) } else { None };
+        next_rank[sa[0]] = 0;
+        for j in 1..n {
+            let (a, b) = (sa[j - 1], sa[j]);
+            next_rank[b] = next_rank[a] + (rank[a] != rank[b] || second(a) != second(b)) as usize;
+        }
+        std::mem::swap(&mut rank, &mut next_rank);
+        if rank[sa[n - 1]] == n - 1 {
+            return sa;
+        }
+        k *= 2;
+    }
+}
+
+// Kasai's algorithm: lcp[i] is the common prefix length of the suffixes at sa[i - 1] and sa[i]
+fn lcp_array(s: &[usize], sa: &[usize]) -> Vec<usize> {
+    let n = s.len();
+    let mut rank = vec![0; n];
+    for (i, &p) in sa.iter().enumerate() {
+        rank[p] = i;
+    }
+    let mut lcp = vec![0; n];
+    let mut h: usize = 0;
+    for p in 0..n {
+        if rank[p] > 0 {
+            let q = sa[rank[p] - 1];
+            while p + h < n && q + h < n && s[p + h] == s[q + h] {
+                h += 1;
+            }
+            lcp[rank[p]] = h;
+            h = h.saturating_sub(1);
+        } else {
+            h = 0;
+        }
+    }
+    lcp
+}
+
+// Bytes saved by turning a set of matches into a pattern
+// `dict_overhead` is the cost of each pattern on top of its data, and each match is replaced by a reference.
+// Leftovers around matches end up as patterns of their own, so cutting chunks up costs too.
+fn matches_save<T: Crunchable>(
     chunks: &[TrackChunk<T>],
+    chunk_sizes: &[Vec<usize>],
     dict_overhead: usize,
-) -> Option<Vec<PatternMatch>> {
-    let matches_save = |m: &Vec<PatternMatch>| ((m[0].length - 1) * m.len()) as isize - dict_overhead as isize;
+    matches: &[PatternMatch],
+) -> isize {
+    let m0 = &matches[0];
+    let pattern_size = (chunk_sizes[m0.chunk_i][m0.start + m0.length] - chunk_sizes[m0.chunk_i][m0.start]) as isize;
+    let refs_size: usize = matches
+        .iter()
+        .map(|m| {
+            PatternRef {
+                transpose: m.transpose,
+                ..PatternRef::new(0)
+            }
+            .size()
+        })
+        .sum();
+    let leftover_size = (dict_overhead + PatternRef::new(0).size()) as isize;
+    let mut leftover_diff: isize = 0;
+    // matches are ordered, so each chunk's matches are next to each other
+    let mut i = 0;
+    while i < matches.len() {
+        let chunk_i = matches[i].chunk_i;
+        let mut last_cut = 0;
+        let mut leftovers = 0;
+        while i < matches.len() && matches[i].chunk_i == chunk_i {
+            if matches[i].start > last_cut {
+                leftovers += 1;
+            }
+            last_cut = matches[i].start + matches[i].length;
+            i += 1;
+        }
+        if last_cut < chunks[chunk_i].chunk.len() {
+            leftovers += 1;
+        }
+        leftover_diff += leftovers - 1; // the chunk itself was already a leftover
+    }
+    pattern_size * (matches.len() as isize - 1)
+        - refs_size as isize
+        - dict_overhead as isize
+        - leftover_diff * leftover_size
+}
 
-    // [ci][ei] => (discovered)
-    let mut chunk_lookups: Vec<_> = chunks
+// Find the set of matches saving the most bytes
+// All chunks are laid out after each other and repeats are found as intervals in their suffix array.
+fn find_best_matches<T: Crunchable>(
+    chunks: &[TrackChunk<T>],
+    transposable: &[bool],
+    dict_overhead: usize,
+) -> Option<(Vec<PatternMatch>, isize)> {
+    // [ci][ei] => size of all elements before ei
+    let chunk_sizes: Vec<Vec<usize>> = chunks
         .iter()
-        .map(|c| vec![HashSet::<usize>::new(); c.chunk.len()])
+        .map(|c| {
+            let mut sizes = vec![0; c.chunk.len() + 1];
+            for (i, e) in c.chunk.iter().enumerate() {
+                sizes[i + 1] = sizes[i] + e.size();
+            }
+            sizes
+        })
         .collect();
 
-    let mut match_sets: Vec<Vec<PatternMatch>> = vec![];
-    for outer in chunks {
-        for outer_ei in 0..outer.chunk.len() {
-            let mut contenders = HashMap::<usize, Vec<PatternMatch>>::new();
-            for (inner_i, inner) in chunks.iter().enumerate() {
-                for inner_ei in 0..inner.chunk.len() {
-                    let max_length = min(inner.chunk.len() - inner_ei, outer.chunk.len() - outer_ei);
-                    for match_i in 0..max_length {
-                        if inner.chunk[inner_ei + match_i] == outer.chunk[outer_ei + match_i] {
-                            let length = match_i + 1;
-                            if !chunk_lookups[inner_i][inner_ei].contains(&length) {
-                                let dst_key = PatternMatch {
-                                    chunk_i: inner_i,
-                                    start: inner_ei,
-                                    length,
-                                };
-                                if let Some(v) = contenders.get_mut(&length) {
-                                    v.push(dst_key.clone());
-                                } else {
-                                    contenders.insert(length, vec![dst_key.clone()]);
-                                }
-                                chunk_lookups[inner_i][inner_ei].insert(length);
-                            }
-                        } else {
-                            break;
-                        }
-                    }
-                }
-            }
-            for match_set in contenders.into_values() {
-                // require at least one match other than the source, and saving at least 1 byte
-                if match_set.len() >= 2 && matches_save(&match_set) >= 1 {
-                    match_sets.push(match_set);
-                }
-            }
+    // lay out the chunks
+    let mut tokens: Vec<Token> = vec![];
+    let mut locations: Vec<(usize, usize)> = vec![]; // (chunk, element) per position
+    for (chunk_i, c) in chunks.iter().enumerate() {
+        let mut prev_note: Option<&T> = None;
+        for (ei, e) in c.chunk.iter().enumerate() {
+            let class = c.classes[ei];
+            tokens.push(if transposable[class] {
+                let token = match prev_note.map(|p| p.relation(e)) {
+                    None => Token::FirstNote,
+                    Some(Relation::Transposed(t)) => Token::Interval(t),
+                    Some(_) => Token::Class(class),
+                };
+                prev_note = Some(e);
+                token
+            } else {
+                Token::Class(class)
+            });
+            locations.push((chunk_i, ei));
         }
+        tokens.push(Token::Separator(chunk_i));
+        locations.push((chunk_i, c.chunk.len()));
     }
+    let n = tokens.len();
+    let ranks: BTreeMap<Token, usize> = {
+        let mut sorted = tokens.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        sorted.into_iter().enumerate().map(|(i, t)| (t, i)).collect()
+    };
+    let s: Vec<usize> = tokens.iter().map(|t| ranks[t]).collect();
+    let sa = suffix_array(&s);
+    let lcp = lcp_array(&s, &sa);
 
-    // remove for overlappers
-    for match_set in &mut match_sets {
-        match_set.sort_by_key(|c| c.start);
-        let mut last_ends = HashMap::<usize, usize>::new();
-        // backwards
-        for match_i in (0..match_set.len()).rev() {
-            let mach = match_set[match_i].clone();
-            if let Some(v) = last_ends.get_mut(&mach.chunk_i) {
-                if mach.start + mach.length > match_set[*v].start {
-                    match_set[*v].length = 0;
-                    *v = match_i;
-                } else {
-                    *v = match_i;
-                }
+    // first transposable position at or after each position, within the chunk
+    let mut first_note = vec![usize::MAX; n];
+    for p in (0..n).rev() {
+        first_note[p] = match tokens[p] {
+            Token::Separator(_) => usize::MAX,
+            Token::FirstNote | Token::Interval(_) => p,
+            Token::Class(class) if transposable[class] => p,
+            Token::Class(_) => first_note.get(p + 1).copied().unwrap_or(usize::MAX),
+        };
+    }
+    let element = |p: usize| &chunks[locations[p].0].chunk[locations[p].1];
+
+    let mut best: Option<(Vec<PatternMatch>, isize)> = None;
+    let mut evaluate = |length: usize, positions: &[usize]| {
+        // extend to the left while all positions agree
+        // the first transposable element of a match is free, but only as long as the next one relates to it equally
+        let mut starts = positions.to_vec();
+        let mut length = length;
+        let mut free_note = true;
+        loop {
+            let p0 = starts[0];
+            if starts.iter().any(|&p| locations[p].1 == 0) {
+                break;
+            }
+            let class0 = chunks[locations[p0].0].classes[locations[p0].1 - 1];
+            let extendable = if transposable[class0] {
+                free_note
+                    && starts.iter().all(|&p| {
+                        let (ci, ei) = locations[p];
+                        transposable[chunks[ci].classes[ei - 1]]
+                    })
             } else {
-                last_ends.insert(mach.chunk_i, match_i);
+                starts.iter().all(|&p| {
+                    let (ci, ei) = locations[p];
+                    chunks[ci].classes[ei - 1] == class0
+                })
+            };
+            if !extendable {
+                break;
+            }
+            if transposable[class0] {
+                free_note = starts.iter().all(|&p| tokens[p - 1] == tokens[p0 - 1]);
             }
+            for p in &mut starts {
+                *p -= 1;
+            }
+            length += 1;
         }
-        for match_i in (0..match_set.len()).rev() {
-            if match_set[match_i].length == 0 {
-                match_set.swap_remove(match_i);
+
+        // non-overlapping, in order
+        starts.sort_unstable();
+        let mut matches: Vec<PatternMatch> = vec![];
+        let mut note0: Option<usize> = None;
+        let mut last_end = 0;
+        for p in starts {
+            if p < last_end && !matches.is_empty() {
+                continue;
             }
+            last_end = p + length;
+            let note = Some(first_note[p]).filter(|&f| f < p + length);
+            let transpose = match (note0, note) {
+                (None, _) => {
+                    note0 = note;
+                    0
+                }
+                (Some(f0), Some(f)) => match element(f0).relation(element(f)) {
+                    Relation::Transposed(t) => t,
+                    _ => 0,
+                },
+                _ => 0,
+            };
+            let (chunk_i, start) = locations[p];
+            matches.push(PatternMatch {
+                chunk_i,
+                start,
+                length,
+                transpose,
+            });
         }
-    }
+        if matches.len() < 2 {
+            return;
+        }
+        let saved = matches_save(chunks, &chunk_sizes, dict_overhead, &matches);
+        if saved >= 1 && best.as_ref().is_none_or(|(_, best_saved)| saved > *best_saved) {
+            best = Some((matches, saved));
+        }
+    };
 
-    // Sort by size saved
-    match_sets.sort_unstable_by_key(|m| -matches_save(m));
-
-    if match_sets.is_empty() {
-        None
-    } else {
-        // Info
-        /*
-        println!("Best crunchables ({} total)", contenders.len());
-        for matches in contenders.iter().take(5) {
-            let m0 = &matches[0];
-            println!(
-                "-> chunk:{}, track:{}, src:{}, length:{} matches:{}, total size:{}, saves:{}",
-                m0.chunk_i,
-                chunks[m0.chunk_i].track_i,
-                m0.start,
-                m0.length,
-                matches.len(),
-                matches.len() * m0.length,
-                matches_save(&matches),
-            );
-            for mach in matches {
-                println!(
-                    "  -> chunk:{}, track:{}, src:{}",
-                    mach.chunk_i, chunks[mach.chunk_i].track_i, mach.start,
-                );
+    // walk all lcp-intervals, i.e. every repeat with all its positions
+    let mut stack: Vec<(usize, usize)> = vec![(0, 0)]; // (lcp, left bound)
+    let mut positions: Vec<usize> = vec![];
+    for i in 1..=n {
+        let cur = if i < n { lcp[i] } else { 0 };
+        let mut lb = i - 1;
+        while cur < stack.last().unwrap().0 {
+            let (length, left) = stack.pop().unwrap();
+            lb = left;
+            positions.clear();
+            positions.extend_from_slice(&sa[left..i]);
+            positions.sort_unstable();
+            evaluate(length, &positions);
+            // periodic repeats overlap themselves, so also try the shortest period
+            let min_gap = positions.windows(2).map(|w| w[1] - w[0]).min().unwrap_or(length);
+            if min_gap < length {
+                evaluate(min_gap, &positions);
             }
         }
-        */
-        Some(match_sets.swap_remove(0))
+        if cur > stack.last().unwrap().0 {
+            stack.push((cur, lb));
+        }
     }
+
+    best
 }
 
 pub fn crunch<T: PartialEq + Clone + Debug>(
@@ -140,50 +394,142 @@ pub fn crunch<T: PartialEq + Clone + Debug>(
     dict_max: usize,
     dict_overhead: usize,
 ) -> (Vec<Vec<T>>, Vec<Vec<usize>>) {
+    let tracks = tracks.into_iter().map(|t| t.into_iter().map(Plain).collect()).collect();
+    let Crunched { dict, usages, .. } =
+        crunch_transposed(tracks, dict_max, dict_overhead, &mut CrunchControl::default());
+    (
+        dict.into_iter().map(|p| p.into_iter().map(|e| e.0).collect()).collect(),
+        usages
+            .into_iter()
+            .map(|t| {
+                t.into_iter()
+                    .flat_map(|r| std::iter::repeat_n(r.pattern_i, r.repeat as usize + 1))
+                    .collect()
+            })
+            .collect(),
+    )
+}
+
+// Shared flag for stopping a crunch from another thread
+#[derive(Clone, Default, Debug)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub type ProgressCallback<'a> = Box<dyn FnMut(&CrunchProgress) + 'a>;
+
+#[derive(Clone, Default, Debug)]
+pub struct CrunchProgress {
+    pub patterns: usize, // patterns found so far, not counting leftovers
+    pub saved: isize,    // bytes saved by those patterns
+    pub fraction: f32,   // rough estimate from 0 to 1, based on the dict size and budgets
+}
+
+// Progress reporting and limits for `crunch_transposed`
+// When cancelled or out of budget, the best result so far is returned.
+#[derive(Default)]
+pub struct CrunchControl<'a> {
+    pub on_progress: Option<ProgressCallback<'a>>,
+    pub cancel: Option<CancelToken>,
+    pub time_budget: Option<Duration>,
+    pub max_iterations: Option<usize>, // max amount of patterns to search for
+}
+
+pub struct Crunched<T> {
+    pub dict: Vec<Vec<T>>,
+    pub usages: Vec<Vec<PatternRef>>,
+    pub saved: Vec<isize>, // bytes saved by each pattern in `dict` - 0 for leftovers
+    pub cancelled: bool,
+    pub out_of_budget: bool, // stopped early because of the time or iteration budget
+}
+
+// Like `crunch`, but also reuses patterns that only differ by a transposition, and scores by serialized size
+pub fn crunch_transposed<T: Crunchable>(
+    tracks: Vec<Vec<T>>,
+    dict_max: usize,
+    dict_overhead: usize,
+    control: &mut CrunchControl,
+) -> Crunched<T> {
+    let start = Instant::now();
     let track_count = tracks.len();
     // convert all tracks to chunks
+    let mut classes = Classes {
+        representatives: vec![],
+        transposable: vec![],
+    };
     let mut chunks: Vec<_> = tracks
         .into_iter()
         .enumerate()
         .map(|(i, t)| TrackChunk {
             track_i: i,
             src_i: 0,
+            classes: t.iter().map(|e| classes.class_of(e)).collect(),
             chunk: t,
         })
         .collect();
 
     let mut dict: Vec<Vec<T>> = vec![];
+    let mut saved: Vec<isize> = vec![];
     let mut dict_uses: Vec<Vec<TrackPattern>> = vec![vec![]; track_count];
+    let mut cancelled = false;
+    let mut out_of_budget = false;
+    let mut report = |dict: &[Vec<T>], saved: &[isize], done: bool| {
+        if let Some(on_progress) = &mut control.on_progress {
+            let mut fraction = dict.len() as f32 / dict_max as f32;
+            if let Some(budget) = control.time_budget {
+                fraction = fraction.max(start.elapsed().as_secs_f32() / budget.as_secs_f32().max(f32::EPSILON));
+            }
+            if let Some(max_iterations) = control.max_iterations {
+                fraction = fraction.max(dict.len() as f32 / max_iterations.max(1) as f32);
+            }
+            on_progress(&CrunchProgress {
+                patterns: dict.len(),
+                saved: saved.iter().sum(),
+                fraction: if done { 1.0 } else { fraction.min(1.0) },
+            });
+        }
+    };
 
     // find chunks and split up until we need the remaining patterns for the leftover chunks
     // TODO: checking chunks.len() here is likely not enough, because the operation we decide to do could explode the amount of chunks
     // For now * 2 as to not max out
     while dict.len() + chunks.len() * 2 < dict_max {
-        //println!("crunching pattern {} | chunk count: {}...", dict.len(), chunks.len());
+        report(&dict, &saved, false);
+        if control.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+            cancelled = true;
+            break;
+        }
+        if control.time_budget.is_some_and(|b| start.elapsed() >= b)
+            || control.max_iterations.is_some_and(|m| dict.len() >= m)
+        {
+            out_of_budget = true;
+            break;
+        }
 
         // find best chunk matches
-        if let Some(matches) = crunchy_chunky(&chunks, dict_overhead) {
-            // create pattern
+        if let Some((matches, pattern_saved)) = find_best_matches(&chunks, &classes.transposable, dict_overhead) {
+            // create pattern from the first match, which the others are transposed relative to
             let pattern = {
                 let m0 = &matches[0];
                 chunks[m0.chunk_i].chunk[m0.start..m0.start + m0.length].to_vec()
             };
             let pattern_i = dict.len();
-            dict.push(pattern.clone());
+            dict.push(pattern);
+            saved.push(pattern_saved);
 
             // group based the chunk they belong in
-            let matches_per_chunk: Vec<(usize, Vec<PatternMatch>)> = matches
-                .into_iter()
-                .fold(HashMap::<usize, Vec<PatternMatch>>::new(), |mut a, m| {
-                    if let Some(cs) = a.get_mut(&m.chunk_i) {
-                        cs.push(m);
-                    } else {
-                        a.insert(m.chunk_i, vec![m]);
-                    }
-                    a
-                })
-                .into_iter()
-                .collect();
+            let mut matches_per_chunk = BTreeMap::<usize, Vec<PatternMatch>>::new();
+            for m in matches {
+                matches_per_chunk.entry(m.chunk_i).or_default().push(m);
+            }
 
             // split the chunks
             // we replace each modified chunk with an empty chunk as to not mess up ordering
@@ -193,66 +539,107 @@ pub fn crunch<T: PartialEq + Clone + Debug>(
                 let track_i = chunks[chunk_i].track_i;
                 let chunk_src_i = chunks[chunk_i].src_i;
                 let mut last_cut: usize = 0;
+                let cut = |chunks: &[TrackChunk<T>], from: usize, to: usize| TrackChunk {
+                    track_i,
+                    src_i: chunk_src_i + from,
+                    chunk: chunks[chunk_i].chunk[from..to].to_vec(),
+                    classes: chunks[chunk_i].classes[from..to].to_vec(),
+                };
                 for mtch in &matches_for_chunk {
                     if mtch.start > last_cut {
-                        chunks.push(TrackChunk {
-                            track_i,
-                            src_i: chunk_src_i + last_cut,
-                            chunk: chunks[chunk_i].chunk[last_cut..mtch.start].to_vec(),
-                        });
+                        chunks.push(cut(&chunks, last_cut, mtch.start));
                     }
                     dict_uses[track_i].push(TrackPattern {
                         src_i: chunk_src_i + mtch.start,
                         pattern_i,
+                        transpose: mtch.transpose,
                     });
                     last_cut = mtch.start + mtch.length;
                 }
-                chunks.push(TrackChunk {
-                    track_i,
-                    src_i: chunk_src_i + last_cut,
-                    chunk: chunks[chunk_i].chunk[last_cut..].to_vec(),
-                });
+                let chunk_len = chunks[chunk_i].chunk.len();
+                chunks.push(cut(&chunks, last_cut, chunk_len));
                 chunks[chunk_i] = TrackChunk {
                     track_i: 0,
                     src_i: 0,
                     chunk: vec![],
+                    classes: vec![],
                 };
             }
 
             // delete empty chunks
-            let mut i: usize = 0;
-            while i < chunks.len() {
-                while i < chunks.len() && chunks[i].chunk.is_empty() {
-                    chunks.swap_remove(i);
-                }
-                i += 1;
-            }
+            chunks.retain(|c| !c.chunk.is_empty());
         } else {
             break;
         }
-        //println!("after:\n -> {:?}\n -> {:?}\n -> {:?} ", dict, chunks, dict_uses);
     }
 
+    report(&dict, &saved, true);
+
     // convert remaining chunks into doct
     for chunk in chunks {
         let pattern_i = dict.len();
         dict.push(chunk.chunk);
+        saved.push(0);
         dict_uses[chunk.track_i].push(TrackPattern {
             src_i: chunk.src_i,
             pattern_i,
+            transpose: 0,
         });
     }
 
     // finalize
-    let usages: Vec<Vec<usize>> = dict_uses
+    let usages: Vec<Vec<PatternRef>> = dict_uses
         .into_iter()
         .map(|mut ct| {
             ct.sort_by_key(|t| t.src_i);
-            ct.into_iter().map(|c| c.pattern_i).collect()
+            collapse_repeats(ct.into_iter().map(|c| PatternRef {
+                transpose: c.transpose,
+                ..PatternRef::new(c.pattern_i)
+            }))
         })
         .collect();
 
-    (dict, usages)
+    Crunched {
+        dict,
+        usages,
+        saved,
+        cancelled,
+        out_of_budget,
+    }
+}
+
+// Merge consecutive identical references into repeats where it saves space
+fn collapse_repeats(refs: impl Iterator<Item = PatternRef>) -> Vec<PatternRef> {
+    let mut out: Vec<PatternRef> = vec![];
+    let mut run: Option<(PatternRef, usize)> = None;
+    let flush = |run: (PatternRef, usize), out: &mut Vec<PatternRef>| {
+        let (r, count) = run;
+        if count > 1 && count * r.size() > (PatternRef { repeat: 1, ..r }).size() {
+            for chunk_start in (0..count).step_by(256) {
+                out.push(PatternRef {
+                    repeat: (min(count - chunk_start, 256) - 1) as u8,
+                    ..r
+                });
+            }
+        } else {
+            out.extend(std::iter::repeat_n(r, count));
+        }
+    };
+    for r in refs {
+        match run {
+            Some((cur, count)) if cur == r => run = Some((cur, count + 1)),
+            _ => {
+                if let Some(prev) = run.take() {
+                    flush(prev, &mut out);
+                }
+                run = Some((r, 1));
+            }
+        }
+    }
+    if let Some(prev) = run {
+        flush(prev, &mut out);
+    }
+    out
 }
 
 pub fn uncrunch<T: Clone>(dict: &[Vec<T>], tracks: &[Vec<usize>]) -> Vec<Vec<T>> {
@@ -262,9 +649,23 @@ pub fn uncrunch<T: Clone>(dict: &[Vec<T>], tracks: &[Vec<usize>]) -> Vec<Vec<T>>
         .collect()
 }
 
+pub fn uncrunch_transposed<T: Crunchable>(dict: &[Vec<T>], tracks: &[Vec<PatternRef>]) -> Vec<Vec<T>> {
+    tracks
+        .iter()
+        .map(|t| {
+            t.iter()
+                .flat_map(|r| {
+                    let pattern = dict[r.pattern_i].iter().map(|e| e.transposed(r.transpose));
+                    std::iter::repeat_n(pattern, r.repeat as usize + 1).flatten()
+                })
+                .collect()
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
-    use rand::Rng;
+    use rand::{Rng, SeedableRng};
 
     use crate::crunch::*;
 
@@ -328,6 +729,101 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_crunch_transposed() {
+        use crate::TrackEvent::{Delta, NoteOn, NotesOff};
+        let riff = |k: u8| {
+            vec![
+                NoteOn(k),
+                Delta(4),
+                NoteOn(k + 3),
+                Delta(4),
+                NoteOn(k + 7),
+                Delta(4),
+                NotesOff,
+            ]
+        };
+        let input = vec![[riff(40), riff(45), riff(40), riff(47)].concat()];
+        let Crunched { dict, usages, .. } = crunch_transposed(input.clone(), 99, 2, &mut CrunchControl::default());
+        assert_eq!(input, uncrunch_transposed(&dict, &usages));
+        assert!(usages[0].iter().any(|r| r.transpose != 0));
+    }
+
+    #[test]
+    fn test_crunch_repeats() {
+        let input = vec![[[1, 2, 3, 4].repeat(32), vec![5, 6]].concat()];
+        let Crunched { dict, usages, .. } = crunch_transposed(
+            input.iter().map(|t| t.iter().map(|e| Plain(*e)).collect()).collect(),
+            99,
+            2,
+            &mut CrunchControl::default(),
+        );
+        assert!(usages[0].iter().any(|r| r.repeat != 0));
+        let output: Vec<Vec<i32>> = uncrunch_transposed(&dict, &usages)
+            .into_iter()
+            .map(|t| t.into_iter().map(|e| e.0).collect())
+            .collect();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn test_crunch_long() {
+        let input: Vec<Vec<u8>> = (0..4)
+            .map(|_| (0..2000).map(|_| rand::thread_rng().gen_range(0..4)).collect())
+            .collect();
+        let (dict, usages) = crunch(input.clone(), 253, 2);
+        assert_eq!(input, uncrunch(&dict, &usages));
+    }
+
+    #[test]
+    fn test_crunch_control() {
+        // seeded so it's always an input which takes more patterns than the budget
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x5eed);
+        let input: Vec<Vec<Plain<u8>>> = (0..4)
+            .map(|_| (0..500).map(|_| Plain(rng.gen_range(0..4))).collect())
+            .collect();
+        assert!(
+            crunch_transposed(input.clone(), 253, 2, &mut CrunchControl::default())
+                .dict
+                .len()
+                > 3
+        );
+
+        // iteration budget keeps a valid result
+        let mut reports = vec![];
+        let crunched = crunch_transposed(
+            input.clone(),
+            253,
+            2,
+            &mut CrunchControl {
+                on_progress: Some(Box::new(|p: &CrunchProgress| reports.push(p.clone()))),
+                max_iterations: Some(3),
+                ..Default::default()
+            },
+        );
+        assert!(crunched.out_of_budget && !crunched.cancelled);
+        assert_eq!(crunched.saved.iter().filter(|s| **s != 0).count(), 3);
+        assert_eq!(input, uncrunch_transposed(&crunched.dict, &crunched.usages));
+        assert_eq!(reports.last().unwrap().fraction, 1.0);
+        assert_eq!(reports.last().unwrap().patterns, 3);
+
+        // cancelling before starting leaves everything as leftovers
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let crunched = crunch_transposed(
+            input.clone(),
+            253,
+            2,
+            &mut CrunchControl {
+                cancel: Some(cancel),
+                ..Default::default()
+            },
+        );
+        assert!(crunched.cancelled);
+        assert_eq!(crunched.dict.len(), input.len());
+        assert_eq!(input, uncrunch_transposed(&crunched.dict, &crunched.usages));
+    }
+
     #[test]
     fn test_crunch_deterministic() {
         let buf: Vec<u8> = (0..16).map(|_| rand::thread_rng().gen_range(0..8)).collect();
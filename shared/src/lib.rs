@@ -1,10 +1,14 @@
 pub mod bounce;
+pub mod config;
 pub mod convert;
-mod crunch;
+pub mod crunch;
+pub mod loudness;
+pub mod optimize;
 pub mod runtime;
+pub mod starter;
 pub mod wasm4_apu;
 
-use std::{ffi::c_void, fmt::Display};
+use std::{collections::BTreeMap, ffi::c_void, fmt::Display};
 
 use anyhow::Result;
 use lazy_static::lazy_static;
@@ -88,6 +92,7 @@ pub enum Pan {
 pub struct ADSR(pub u8, pub u8, pub u8, pub u8);
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct PitchEnv {
     pub note_offset: i8,
     pub duration: u8,
@@ -112,6 +117,7 @@ pub struct Delay {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Arpeggio {
     pub rate: u8,
     // TODO: extend arpeggio:
@@ -121,6 +127,7 @@ pub struct Arpeggio {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Vibrato {
     pub speed: u8,
     pub depth: u8,
@@ -128,50 +135,271 @@ pub struct Vibrato {
     // - ramp/delay to progressively increase depth
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Tremolo {
+    pub speed: u8,
+    pub depth: u8,
+}
+
+// Cycles the pulse duty every `rate` ticks - only used by pulse channels
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DutySequence {
+    pub rate: u8,
+    pub steps: Vec<PulseDuty>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum MacroKind {
+    Volume = W4ON2_MACRO_VOLUME as isize,
+    Arpeggio = W4ON2_MACRO_ARPEGGIO as isize,
+    Pitch = W4ON2_MACRO_PITCH as isize,
+    Duty = W4ON2_MACRO_DUTY as isize,
+}
+impl MacroKind {
+    pub fn types() -> [MacroKind; W4ON2_MACRO_KIND_COUNT as usize] {
+        [
+            MacroKind::Volume,
+            MacroKind::Arpeggio,
+            MacroKind::Pitch,
+            MacroKind::Duty,
+        ]
+    }
+}
+
+pub trait MacroValue: Copy {
+    fn to_byte(self) -> u8;
+}
+impl MacroValue for u8 {
+    fn to_byte(self) -> u8 {
+        self
+    }
+}
+impl MacroValue for i8 {
+    fn to_byte(self) -> u8 {
+        self as u8
+    }
+}
+impl MacroValue for PulseDuty {
+    fn to_byte(self) -> u8 {
+        self as u8
+    }
+}
+
+// Tracker-style per-tick sequence
+// Loops between `loop_point` and the release point (or the end) while held, and plays from `release_point` once released
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Macro<T> {
+    pub values: Vec<T>,
+    #[serde(default, rename = "loop", skip_serializing_if = "Option::is_none")]
+    pub loop_point: Option<u8>,
+    #[serde(default, rename = "release", skip_serializing_if = "Option::is_none")]
+    pub release_point: Option<u8>,
+}
+impl<T: MacroValue> Macro<T> {
+    fn to_data(&self) -> Option<MacroData> {
+        if self.values.is_empty() {
+            None
+        } else {
+            Some(MacroData {
+                values: self.values.iter().map(|v| v.to_byte()).collect(),
+                loop_point: self.loop_point,
+                release_point: self.release_point,
+            })
+        }
+    }
+}
+
+// Volume values scale from 0 to W4ON2_MACRO_VOLUME_MAX, arpeggio values are in semitones, and pitch values in 1/16th semitones
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Macros {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<Macro<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arpeggio: Option<Macro<i8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pitch: Option<Macro<i8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duty: Option<Macro<PulseDuty>>,
+}
+impl Macros {
+    fn to_data(&self) -> [Option<MacroData>; W4ON2_MACRO_KIND_COUNT as usize] {
+        [
+            self.volume.as_ref().and_then(Macro::to_data),
+            self.arpeggio.as_ref().and_then(Macro::to_data),
+            self.pitch.as_ref().and_then(Macro::to_data),
+            self.duty.as_ref().and_then(Macro::to_data),
+        ]
+    }
+}
+
+// Macro as stored in the w4on2 file
+#[derive(Clone, Debug, PartialEq)]
+pub struct MacroData {
+    pub values: Vec<u8>,
+    pub loop_point: Option<u8>,
+    pub release_point: Option<u8>,
+}
+impl MacroData {
+    pub fn serialize_into(&self, into: &mut Vec<u8>) {
+        assert!(!self.values.is_empty());
+        assert!(self.values.len() <= W4ON2_MAX_MACRO_LENGTH as usize);
+        into.extend([
+            self.values.len() as u8,
+            self.loop_point.unwrap_or(W4ON2_MACRO_NONE as u8),
+            self.release_point.unwrap_or(W4ON2_MACRO_NONE as u8),
+        ]);
+        into.extend(&self.values);
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
-#[serde(default, rename_all = "snake_case")]
+#[serde(default, rename_all = "snake_case", deny_unknown_fields)]
 pub struct SongTrackConfig {
     pub nickname: String,
+    // When converting, take events from the MIDI track with this name instead of this MIDI channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub midi_track: Option<String>,
+    pub match_nickname: bool, // like `midi_track`, using `nickname` as the name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>, // what the other fields are based on, see `config::BUILTIN_PRESETS`
     pub channel: Channel,
     pub volume: u8,
     pub adsr: ADSR,
+    pub release_effects: bool, // keep pitch effects and arpeggio running during Release
     pub pitch_env: PitchEnv,
     pub portamento: u8,
+    pub glide: u8,
     pub arpeggio: Arpeggio,
     pub vibrato: Vibrato,
+    pub tremolo: Tremolo,
+    pub duty_sequence: DutySequence,
+    pub macros: Macros,
     // TODO: delay: Option<Delay>,
 }
 impl Default for SongTrackConfig {
     fn default() -> Self {
         Self {
             nickname: "".to_owned(),
+            midi_track: None,
+            match_nickname: false,
+            preset: None,
             channel: Channel::Pulse1(PulseDuty::D12_5),
             volume: W4ON2_VOLUME_MAX as u8,
             adsr: ADSR(0, 0, W4ON2_SUSTAIN_MAX as u8, 0),
+            release_effects: false,
             pitch_env: PitchEnv::default(),
             portamento: 0,
+            glide: 0,
             arpeggio: Arpeggio::default(),
             vibrato: Vibrato::default(),
+            tremolo: Tremolo::default(),
+            duty_sequence: DutySequence::default(),
+            macros: Macros::default(),
         }
     }
 }
+impl SongTrackConfig {
+    pub fn matches_by_name(&self) -> bool {
+        self.midi_track.is_some() || self.match_nickname
+    }
+    // MIDI track names are compared ignoring case and surrounding whitespace
+    pub fn matches_track_name(&self, name: &str) -> bool {
+        let name = name.trim();
+        let matches = |s: &str| !s.trim().is_empty() && s.trim().eq_ignore_ascii_case(name);
+        self.midi_track.as_deref().is_some_and(matches) || (self.match_nickname && matches(&self.nickname))
+    }
+}
 lazy_static! {
     pub static ref SONG_TRACK_CONFIG_DEFAULT: SongTrackConfig = SongTrackConfig::default();
 }
 
-#[derive(Serialize, Deserialize, Default, Clone)]
-pub struct SongConfig {
-    pub channels: [SongTrackConfig; 16],
+// How MIDI note times are rounded to WASM-4 ticks
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum QuantizeMode {
+    #[default]
+    Nearest,
+    Floor,
+    PreserveDuration, // note starts are rounded to the nearest tick, and ends keep the rounded note length
 }
-impl SongConfig {
-    pub fn from_toml(toml_str: &str) -> Result<Self> {
-        Ok(toml::from_str::<SongConfig>(toml_str)?)
+impl QuantizeMode {
+    pub fn types() -> [QuantizeMode; 3] {
+        [
+            QuantizeMode::Nearest,
+            QuantizeMode::Floor,
+            QuantizeMode::PreserveDuration,
+        ]
+    }
+}
+impl Display for QuantizeMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            QuantizeMode::Nearest => "Nearest",
+            QuantizeMode::Floor => "Floor",
+            QuantizeMode::PreserveDuration => "Preserve duration",
+        })
     }
-    pub fn to_toml(&self) -> Result<String> {
-        Ok(toml::to_string(self)?)
+}
+
+// Delays every second `1/divisions` of a beat, `amount` is in percent of the pair where 50 is straight
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Swing {
+    pub divisions: u8,
+    pub amount: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuantizeConfig {
+    pub mode: QuantizeMode,
+    pub min_note_ticks: u8, // notes are held at least this long, unless the next note starts first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swing: Option<Swing>,
+}
+impl Default for QuantizeConfig {
+    fn default() -> Self {
+        Self {
+            mode: QuantizeMode::default(),
+            min_note_ticks: 1,
+            swing: None,
+        }
     }
 }
 
+// Changes to a channel's instrument from some point in the song, until the next change of the same fields
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Automation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bar: Option<usize>, // counting from 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beat: Option<f64>, // within the bar, counting from 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub marker: Option<String>, // at every MIDI marker or cue point with this name, instead of a bar
+    pub channel: usize,
+    pub set: toml::Table, // fields like in `SongTrackConfig`
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct SongConfig {
+    #[serde(default)]
+    pub quantize: QuantizeConfig,
+    #[serde(default)]
+    pub includes: Vec<String>, // TOML files to take presets from
+    #[serde(default)]
+    pub presets: BTreeMap<String, SongTrackConfig>, // defined in this song
+    #[serde(default)]
+    pub included_presets: BTreeMap<String, SongTrackConfig>,
+    pub channels: [SongTrackConfig; 16],
+    #[serde(default)]
+    pub automation: Vec<Automation>,
+}
+
 // NOTE: This enum is what is serialized into w4on2 track events
 // The events should correspond to events in `w4on2.h`, but types can be whatever makes most sense.
 #[derive(Debug, Clone, PartialEq)]
@@ -180,7 +408,7 @@ pub enum TrackEvent {
     DeltaNotesOff(usize), // Delta(...) followed by a NotesOff - used for more efficient storage by `convert`
     NoteOn(u8), // trigger a note - if gotten before "NotesOff", will act as slide or arpeggio depending on instrument
     NotesOff,   // when all notes on this track have ended
-    SetFlags(u8), // channel, pulse, pan
+    SetFlags(u8), // channel, pulse, pan, W4ON2_FLAG_*
     SetVolume(u8),
     SetPan(Pan),
     SetVelocity(u8),
@@ -192,7 +420,13 @@ pub enum TrackEvent {
     SetPitchEnv(PitchEnv),
     SetArpeggio(Arpeggio),
     SetPortamento(u8),
+    SetGlide(u8),  // portamento from the previous note, even if it was released
+    Slide(i8, u8), // slide the current note by some semitones over some ticks
     SetVibrato(Vibrato),
+    SetTremolo(Tremolo),
+    SetDutySequence(DutySequence),
+    // index into the song macros
+    SetMacro(MacroKind, Option<u8>),
     //SetDelay(Delay),
 }
 impl TrackEvent {
@@ -211,6 +445,10 @@ impl TrackEvent {
             TrackEvent::SetPortamento(p) => {
                 into.extend([W4ON2_FMT_SET_PORTAMENTO_ARG1_ID as u8, *p]);
             }
+            TrackEvent::SetGlide(g) => into.extend([W4ON2_FMT_SET_GLIDE_ARG1_ID as u8, *g]),
+            TrackEvent::Slide(semitones, ticks) => {
+                into.extend([W4ON2_FMT_SLIDE_ARG2_ID as u8, *semitones as u8, *ticks])
+            }
             TrackEvent::Delta(d) => {
                 assert!(*d > 0);
                 assert!(*d <= 0xffff);
@@ -244,9 +482,88 @@ impl TrackEvent {
             TrackEvent::SetS(s) => into.extend([W4ON2_FMT_SET_S_ARG1_ID as u8, *s]),
             TrackEvent::SetR(r) => into.extend([W4ON2_FMT_SET_R_ARG1_ID as u8, *r]),
             TrackEvent::SetVibrato(v) => into.extend([W4ON2_FMT_SET_VIBRATO_ARG2_ID as u8, v.speed, v.depth]),
+            TrackEvent::SetTremolo(t) => into.extend([W4ON2_FMT_SET_TREMOLO_ARG2_ID as u8, t.speed, t.depth]),
+            TrackEvent::SetDutySequence(ds) => {
+                if ds.rate == 0 || ds.steps.is_empty() {
+                    into.extend([W4ON2_FMT_SET_DUTY_SEQ_ARG2_ID as u8, 0, 0]);
+                } else {
+                    assert!(ds.rate <= W4ON2_DUTY_SEQ_RATE_MAX as u8);
+                    assert!(ds.steps.len() <= W4ON2_DUTY_SEQ_MAX_STEPS as usize);
+                    let steps = ds
+                        .steps
+                        .iter()
+                        .enumerate()
+                        .fold(0u8, |a, (i, d)| a | ((*d as u8) << (i * 2)));
+                    let rate = ds.rate | ((ds.steps.len() as u8 - 1) << 6);
+                    into.extend([W4ON2_FMT_SET_DUTY_SEQ_ARG2_ID as u8, steps, rate]);
+                }
+            }
+            TrackEvent::SetMacro(kind, m) => into.extend([
+                W4ON2_FMT_SET_MACRO_ARG2_ID as u8,
+                *kind as u8,
+                m.unwrap_or(W4ON2_MACRO_NONE as u8),
+            ]),
         };
     }
 }
+impl crunch::Crunchable for TrackEvent {
+    fn relation(&self, other: &Self) -> crunch::Relation {
+        match (self, other) {
+            (TrackEvent::NoteOn(a), TrackEvent::NoteOn(b)) => {
+                crunch::Relation::Transposed((*b as i16 - *a as i16) as i8)
+            }
+            _ if self == other => crunch::Relation::Equal,
+            _ => crunch::Relation::Different,
+        }
+    }
+    fn transposed(&self, semitones: i8) -> Self {
+        match self {
+            TrackEvent::NoteOn(key) => TrackEvent::NoteOn((*key as i16 + semitones as i16) as u8),
+            _ => self.clone(),
+        }
+    }
+    fn size(&self) -> usize {
+        let mut buf = Vec::with_capacity(8);
+        self.serialize_into(&mut buf);
+        buf.len()
+    }
+}
+
+// Entry in a track's pattern list
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PatternRef {
+    pub pattern_i: usize,
+    pub transpose: i8, // semitones added to NoteOn keys
+    pub volume: i8,    // offset added to the track volume while this pattern plays
+    pub repeat: u8,    // extra plays after the first
+}
+impl PatternRef {
+    pub fn new(pattern_i: usize) -> Self {
+        Self {
+            pattern_i,
+            ..Default::default()
+        }
+    }
+    pub fn serialize_into(&self, into: &mut Vec<u8>) {
+        if self.repeat != 0 {
+            into.extend([W4ON2_TRACK_REPEAT_ARG1_ID as u8, self.repeat]);
+        }
+        if self.volume != 0 {
+            into.extend([W4ON2_TRACK_VOLUME_ARG1_ID as u8, self.volume as u8]);
+        }
+        if self.transpose != 0 {
+            into.extend([W4ON2_TRACK_TRANSPOSE_ARG1_ID as u8, self.transpose as u8]);
+        }
+        assert!(self.pattern_i < W4ON2_MAX_PATTERNS as usize);
+        into.push(self.pattern_i as u8);
+    }
+    // serialized size in a track's pattern list
+    pub fn size(&self) -> usize {
+        let mut buf = Vec::with_capacity(7);
+        self.serialize_into(&mut buf);
+        buf.len()
+    }
+}
 
 pub fn optimal_bpm(midi_bpm: f64, timesig_num: i32, timesig_denom: i32) -> (f64, usize) {
     if midi_bpm == 0.0 || timesig_num == 0 || timesig_denom == 0 {
@@ -258,20 +575,46 @@ pub fn optimal_bpm(midi_bpm: f64, timesig_num: i32, timesig_denom: i32) -> (f64,
     }
 }
 
+// Where the counts are in the header, after the total size
+const HEADER_PATTERN_COUNT_I: usize = 2;
+const HEADER_TRACK_COUNT_I: usize = 3;
+const HEADER_MACRO_COUNT_I: usize = 4;
+
 // Struct that gets serialized into a complete w4on2 song
 pub struct W4PlayerSong {
     pub patterns: Vec<Vec<TrackEvent>>,
-    pub tracks: Vec<Vec<usize>>, // indices into patterns
+    pub tracks: Vec<Vec<PatternRef>>,
+    pub macros: Vec<MacroData>,
 }
 impl W4PlayerSong {
+    // number of tracks in serialized song bytes
+    pub fn track_count(w4on2_bytes: &[u8]) -> u8 {
+        w4on2_bytes.get(HEADER_TRACK_COUNT_I).copied().unwrap_or(0)
+    }
+    // serialized size in bytes, also for songs too large to be serialized
+    pub fn size(&self) -> usize {
+        let mut macro_buf = vec![];
+        for m in &self.macros {
+            m.serialize_into(&mut macro_buf);
+        }
+        let pattern_size: usize = self.patterns.iter().flatten().map(crunch::Crunchable::size).sum();
+        let track_size: usize = self.tracks.iter().flatten().map(PatternRef::size).sum();
+        W4ON2_HEADER_SIZE as usize
+            + 2 * (self.patterns.len() + self.tracks.len() + self.macros.len())
+            + macro_buf.len()
+            + pattern_size
+            + track_size
+    }
     pub fn serialize(&self) -> Vec<u8> {
         // init with total size to be replaced
-        let mut out: Vec<u8> = vec![0, 0];
-        // pattern/track counts
+        let mut out: Vec<u8> = vec![0; W4ON2_HEADER_SIZE as usize];
+        // pattern/track/macro counts
         assert!(self.patterns.len() <= W4ON2_MAX_PATTERNS as usize);
-        out.push(self.patterns.len() as u8);
+        out[HEADER_PATTERN_COUNT_I] = self.patterns.len() as u8;
         assert!(self.tracks.len() <= W4ON2_TRACK_COUNT as usize);
-        out.push(self.tracks.len() as u8);
+        out[HEADER_TRACK_COUNT_I] = self.tracks.len() as u8;
+        assert!(self.macros.len() <= W4ON2_MAX_MACROS as usize);
+        out[HEADER_MACRO_COUNT_I] = self.macros.len() as u8;
         // offset placeholders
         let mut pattern_offset_is = vec![0; self.patterns.len()];
         for ix in &mut pattern_offset_is {
@@ -283,7 +626,20 @@ impl W4PlayerSong {
             *ix = out.len();
             out.extend([0, 0]);
         }
-        // insert
+        let mut macro_offset_is = vec![0; self.macros.len()];
+        for ix in &mut macro_offset_is {
+            *ix = out.len();
+            out.extend([0, 0]);
+        }
+        // insert - macros first since their size is stored within them, patterns and tracks end where the next begins
+        for (i, m) in self.macros.iter().enumerate() {
+            assert!(out.len() <= 0xffff);
+            out.splice(
+                macro_offset_is[i]..macro_offset_is[i] + 2,
+                (out.len() as u16).to_be_bytes(),
+            ); // replace offset
+            m.serialize_into(&mut out);
+        }
         for (i, p) in self.patterns.iter().enumerate() {
             assert!(out.len() <= 0xffff);
             out.splice(
@@ -301,8 +657,7 @@ impl W4PlayerSong {
                 (out.len() as u16).to_be_bytes(),
             ); // replace offset
             for ptn in t {
-                assert!(*ptn <= 255);
-                out.push(*ptn as u8);
+                ptn.serialize_into(&mut out);
             }
         }
         // replace start size
@@ -320,6 +675,11 @@ struct MidiEventMapperTrack {
     cur_key: u8,
     cur_pan: Pan,
     want_pan: Pan,
+    sounding: bool,
+    slide_ticks: u8,
+    pending_slide: Option<TrackEvent>, // slide requested before the note it applies to
+    cur_macros: [Option<u8>; W4ON2_MACRO_KIND_COUNT as usize],
+    want_macros: [Option<u8>; W4ON2_MACRO_KIND_COUNT as usize],
 }
 impl Default for MidiEventMapperTrack {
     fn default() -> Self {
@@ -330,13 +690,27 @@ impl Default for MidiEventMapperTrack {
             cur_key: Default::default(),
             cur_pan: Pan::Stereo,
             want_pan: Pan::Stereo,
+            sounding: false,
+            slide_ticks: 0,
+            pending_slide: None,
+            cur_macros: Default::default(),
+            want_macros: Default::default(),
         }
     }
 }
 
+// General MIDI "Effects 2 Depth" (formerly "Tremolo Depth")
+pub const MIDI_CC_TREMOLO_DEPTH: u8 = 92;
+// Undefined in General MIDI, like the slide ones
+pub const MIDI_CC_TREMOLO_SPEED: u8 = 22;
+// Undefined in General MIDI - slide amount in semitones centered around 64, and slide duration in ticks
+pub const MIDI_CC_SLIDE: u8 = 20;
+pub const MIDI_CC_SLIDE_TICKS: u8 = 21;
+
 // Takes care of sending instrument parameters as required, keeping track of notes, and other playback state
 pub struct MidiEventMapper {
     tracks: [MidiEventMapperTrack; 16],
+    macros: Vec<MacroData>,
 }
 impl Default for MidiEventMapper {
     fn default() -> Self {
@@ -348,21 +722,55 @@ impl MidiEventMapper {
     pub fn new() -> Self {
         Self {
             tracks: Default::default(),
+            macros: vec![],
         }
     }
     // TODO: don't require ownership?
     pub fn set_tracks(&mut self, tracks: [SongTrackConfig; 16]) {
+        self.macros.clear();
         for (i, t) in tracks.into_iter().enumerate() {
-            self.tracks[i].want_conf = t;
+            // at most one macro of each kind per track, which is far from the limit
+            self.set_track(i, t).unwrap();
+        }
+    }
+    fn set_track(&mut self, track_i: usize, conf: SongTrackConfig) -> Result<(), String> {
+        // macros are shared between tracks, so identical ones are only stored once
+        for (kind_i, m) in conf.macros.to_data().into_iter().enumerate() {
+            self.tracks[track_i].want_macros[kind_i] = match m {
+                Some(m) => {
+                    let macro_i = match self.macros.iter().position(|e| *e == m) {
+                        Some(macro_i) => macro_i,
+                        None if self.macros.len() < W4ON2_MAX_MACROS as usize => {
+                            self.macros.push(m);
+                            self.macros.len() - 1
+                        }
+                        None => return Err(format!("more than {} distinct macros", W4ON2_MAX_MACROS)),
+                    };
+                    Some(macro_i as u8)
+                }
+                None => None,
+            };
         }
+        self.tracks[track_i].want_conf = conf;
+        Ok(())
+    }
+    // Macros referenced by `TrackEvent::SetMacro` events
+    pub fn macros(&self) -> &[MacroData] {
+        &self.macros
     }
     fn maybe_init(&mut self, into: &mut Vec<TrackEvent>, track_i: u8) {
         let track = &mut self.tracks[track_i as usize];
         let w = &track.want_conf;
         let c = &mut track.cur_conf;
-        if c.channel != w.channel {
-            into.push(TrackEvent::SetFlags(w.channel.to_wasm4_flags()));
+        if c.channel != w.channel || c.release_effects != w.release_effects {
+            // pan lives in the same flags, so keep it as is
+            let mut flags = w.channel.to_wasm4_flags() | ((track.cur_pan as u8) << 4);
+            if w.release_effects {
+                flags |= W4ON2_FLAG_RELEASE_EFFECTS as u8;
+            }
+            into.push(TrackEvent::SetFlags(flags));
             c.channel = w.channel.clone();
+            c.release_effects = w.release_effects;
         }
         if c.volume != w.volume {
             into.push(TrackEvent::SetVolume(w.volume));
@@ -380,6 +788,10 @@ impl MidiEventMapper {
             into.push(TrackEvent::SetPortamento(w.portamento));
             c.portamento = w.portamento;
         }
+        if c.glide != w.glide {
+            into.push(TrackEvent::SetGlide(w.glide));
+            c.glide = w.glide;
+        }
         if c.pitch_env != w.pitch_env {
             into.push(TrackEvent::SetPitchEnv(w.pitch_env.clone()));
             c.pitch_env = w.pitch_env.clone();
@@ -388,6 +800,21 @@ impl MidiEventMapper {
             into.push(TrackEvent::SetVibrato(w.vibrato.clone()));
             c.vibrato = w.vibrato.clone();
         }
+        if c.tremolo != w.tremolo {
+            into.push(TrackEvent::SetTremolo(w.tremolo.clone()));
+            c.tremolo = w.tremolo.clone();
+        }
+        if c.duty_sequence != w.duty_sequence {
+            into.push(TrackEvent::SetDutySequence(w.duty_sequence.clone()));
+            c.duty_sequence = w.duty_sequence.clone();
+        }
+        for kind in MacroKind::types() {
+            let kind_i = kind as usize;
+            if track.cur_macros[kind_i] != track.want_macros[kind_i] {
+                into.push(TrackEvent::SetMacro(kind, track.want_macros[kind_i]));
+                track.cur_macros[kind_i] = track.want_macros[kind_i];
+            }
+        }
     }
     pub fn note_on(&mut self, into: &mut Vec<TrackEvent>, midi_ch: u8, midi_key: u8, vel: u8) {
         self.maybe_init(into, midi_ch);
@@ -402,14 +829,53 @@ impl MidiEventMapper {
             into.push(TrackEvent::SetPan(track.want_pan));
         }
         into.push(TrackEvent::NoteOn(midi_key));
+        // the runtime resets slides on completely new notes
+        if !track.sounding {
+            track.sounding = true;
+            if let Some(slide) = track.pending_slide.take() {
+                into.push(slide);
+            }
+        }
     }
     pub fn note_off(&mut self, into: &mut Vec<TrackEvent>, midi_ch: u8, midi_key: u8) {
         self.maybe_init(into, midi_ch);
         let track = &mut self.tracks[midi_ch as usize];
         if track.cur_key == midi_key {
+            track.sounding = false;
             into.push(TrackEvent::NotesOff);
         }
     }
+    // `semitones` is centered around 64
+    pub fn slide(&mut self, into: &mut Vec<TrackEvent>, midi_ch: u8, semitones: u8) {
+        self.maybe_init(into, midi_ch);
+        let track = &mut self.tracks[midi_ch as usize];
+        let slide = TrackEvent::Slide(semitones as i8 - 64, track.slide_ticks);
+        if track.sounding {
+            into.push(slide);
+        } else {
+            track.pending_slide = Some(slide);
+        }
+    }
+    pub fn slide_ticks(&mut self, midi_ch: u8, ticks: u8) {
+        self.tracks[midi_ch as usize].slide_ticks = ticks;
+    }
+    // Unlike pan, this applies immediately so it can be automated during sustained notes
+    pub fn tremolo_depth(&mut self, into: &mut Vec<TrackEvent>, midi_ch: u8, depth: u8) {
+        self.tracks[midi_ch as usize].want_conf.tremolo.depth =
+            ((depth as u32 * W4ON2_TREMOLO_DEPTH_MAX) / W4ON2_VELOCITY_MAX) as u8;
+        self.maybe_init(into, midi_ch);
+    }
+    pub fn tremolo_speed(&mut self, into: &mut Vec<TrackEvent>, midi_ch: u8, speed: u8) {
+        self.tracks[midi_ch as usize].want_conf.tremolo.speed =
+            ((speed as u32 * u8::MAX as u32) / W4ON2_VELOCITY_MAX) as u8;
+        self.maybe_init(into, midi_ch);
+    }
+    // Switches to another config for the track, applying immediately like `tremolo_depth`
+    pub fn automate(&mut self, into: &mut Vec<TrackEvent>, midi_ch: u8, conf: SongTrackConfig) -> Result<(), String> {
+        self.set_track(midi_ch as usize, conf)?;
+        self.maybe_init(into, midi_ch);
+        Ok(())
+    }
     pub fn pan(&mut self, midi_ch: u8, pan: u8) {
         self.tracks[midi_ch as usize].want_pan = if pan < 43 {
             Pan::Left
@@ -420,3 +886,30 @@ impl MidiEventMapper {
         };
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderOptions {
+    pub stretch: bool,
+    pub crunch: bool, // doesn't change how it sounds, only the size in the report
+    pub bounce: bounce::BounceOptions,
+}
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            stretch: true,
+            crunch: true, // like `convert`, so the size in the report is the real one
+            bounce: bounce::BounceOptions::default(),
+        }
+    }
+}
+
+// Converts and bounces in one go without writing the w4on2 file anywhere, the report has the size it would have
+pub fn render(
+    conf: &SongConfig,
+    midi_bytes: &[u8],
+    options: &RenderOptions,
+) -> Result<(bounce::Bounce, convert::ConvertReport)> {
+    options.bounce.validate()?;
+    let (w4on2_bytes, report) = convert::convert(conf, midi_bytes, options.stretch, options.crunch)?;
+    Ok((bounce::bounce(&w4on2_bytes, &options.bounce)?, report))
+}
@@ -0,0 +1,123 @@
+// Integrated loudness according to ITU-R BS.1770-4, in LUFS
+
+const BLOCK_SECONDS: f64 = 0.4;
+const BLOCK_STEP: f64 = 0.25; // of a block, i.e. 75% overlap
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: b.map(|b| b / a[0]),
+            a: [a[1] / a[0], a[2] / a[0]],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+// The K-weighting filter, a high shelf followed by a high pass, with the coefficients worked out for any sample rate
+// like libebur128 does, which matches the ones given for 48 kHz in the recommendation.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    let (gain, q, fc) = (3.999843853973347, 0.7071752369554196, 1681.974450955533);
+    let k = (std::f64::consts::PI * fc / fs).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let shelf = Biquad::new(
+        [vh + vb * k / q + k * k, 2.0 * (k * k - vh), vh - vb * k / q + k * k],
+        [1.0 + k / q + k * k, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+    );
+
+    let (q, fc) = (0.5003270373238773, 38.13547087602444);
+    let k = (std::f64::consts::PI * fc / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [a0, -2.0 * a0, a0], // unnormalized, like in the recommendation
+        [a0, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+    );
+    [shelf, high_pass]
+}
+
+fn block_loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+// `samples` are interleaved stereo, None if it's silent or shorter than a block
+pub fn integrated_loudness(samples: &[f32], sample_rate: u32) -> Option<f64> {
+    // mean square of the K-weighted samples, summed over both channels
+    let mut filters = [k_weighting(sample_rate), k_weighting(sample_rate)];
+    let squared: Vec<f64> = samples
+        .chunks_exact(2)
+        .map(|frame| {
+            frame.iter().zip(&mut filters).fold(0.0, |sum, (x, filter)| {
+                let y = filter.iter_mut().fold(*x as f64, |x, stage| stage.process(x));
+                sum + y * y
+            })
+        })
+        .collect();
+
+    let block_len = (BLOCK_SECONDS * sample_rate as f64).round() as usize;
+    let step = ((block_len as f64 * BLOCK_STEP).round() as usize).max(1);
+    if block_len == 0 || squared.len() < block_len {
+        return None;
+    }
+    let mut sum: f64 = squared[..block_len].iter().sum();
+    let mut blocks = vec![sum / block_len as f64];
+    let mut start = 0;
+    while start + step + block_len <= squared.len() {
+        sum += squared[start + block_len..start + step + block_len].iter().sum::<f64>();
+        sum -= squared[start..start + step].iter().sum::<f64>();
+        start += step;
+        blocks.push(sum.max(0.0) / block_len as f64);
+    }
+
+    let gated_mean = |gate: f64| {
+        let gated: Vec<f64> = blocks
+            .iter()
+            .copied()
+            .filter(|power| *power > 0.0 && block_loudness(*power) > gate)
+            .collect();
+        (!gated.is_empty()).then(|| gated.iter().sum::<f64>() / gated.len() as f64)
+    };
+    let relative_gate = block_loudness(gated_mean(ABSOLUTE_GATE)?) + RELATIVE_GATE;
+    Some(block_loudness(gated_mean(relative_gate.max(ABSOLUTE_GATE))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_integrated_loudness() {
+        // a full scale 997 Hz sine in both channels is 0 LUFS
+        for sample_rate in [44100, 48000] {
+            let mut samples = vec![];
+            for i in 0..sample_rate as usize * 3 {
+                let x = (2.0 * std::f64::consts::PI * 997.0 * i as f64 / sample_rate as f64).sin() as f32;
+                samples.extend([x, x]);
+            }
+            let loudness = integrated_loudness(&samples, sample_rate).unwrap();
+            assert!(loudness.abs() < 0.05, "{} Hz: {} LUFS", sample_rate, loudness);
+            let half: Vec<f32> = samples.iter().map(|x| x * 0.5).collect();
+            let loudness = integrated_loudness(&half, sample_rate).unwrap();
+            assert!((loudness + 6.02).abs() < 0.05, "{} Hz: {} LUFS", sample_rate, loudness);
+        }
+        assert_eq!(integrated_loudness(&[0.0; 48000 * 2], 48000), None);
+    }
+}
@@ -0,0 +1,525 @@
+use crate::{runtime::*, Pan, TrackEvent, ADSR};
+
+// Peephole optimizations of track events which don't change how the song sounds
+// Tracks are looked at as points in time, each with some events followed by a wait.
+#[derive(Debug, Default)]
+struct Point {
+    events: Vec<TrackEvent>,
+    wait: usize,
+}
+
+fn to_points(track: &[TrackEvent]) -> Vec<Point> {
+    let mut points = vec![Point::default()];
+    for e in track {
+        let last = points.last_mut().unwrap();
+        match e {
+            TrackEvent::Delta(d) => last.wait += d,
+            TrackEvent::DeltaNotesOff(d) => {
+                last.wait += d;
+                points.push(Point {
+                    events: vec![TrackEvent::NotesOff],
+                    wait: 0,
+                });
+            }
+            e => {
+                if last.wait > 0 {
+                    points.push(Point::default());
+                }
+                points.last_mut().unwrap().events.push(e.clone());
+            }
+        }
+    }
+    points
+}
+
+fn starts_with_notes_off(point: Option<&Point>) -> bool {
+    point.is_some_and(|p| p.events.first() == Some(&TrackEvent::NotesOff))
+}
+
+// Split a wait into as few bytes as possible, ending with a NotesOff if wanted
+fn encode_wait(into: &mut Vec<TrackEvent>, wait: usize, notes_off: bool) {
+    let short = W4ON2_FMT_SHORT_DELTA_2_COUNT as usize;
+    let short_notes_off = W4ON2_FMT_SHORT_DELTA_NOTES_OFF_3_COUNT as usize;
+    let long = 0xffff;
+    let last = if !notes_off || wait == 0 {
+        0
+    } else if wait <= short_notes_off + short {
+        wait.min(short_notes_off)
+    } else {
+        wait.min(long)
+    };
+    let mut rest = wait - last;
+    while rest > 0 {
+        let d = if rest > short && rest <= 2 * short {
+            rest - short
+        } else {
+            rest.min(long)
+        };
+        into.push(TrackEvent::Delta(d));
+        rest -= d;
+    }
+    if last > 0 {
+        into.push(TrackEvent::DeltaNotesOff(last));
+    }
+}
+
+// Bytes needed for a wait, including the NotesOff after it if any
+fn wait_size(wait: usize, notes_off: bool) -> usize {
+    let mut events = vec![];
+    encode_wait(&mut events, wait, notes_off);
+    let mut buf = vec![];
+    for e in &events {
+        e.serialize_into(&mut buf);
+    }
+    buf.len() + (notes_off && wait == 0) as usize
+}
+
+fn from_points(points: &[Point]) -> Vec<TrackEvent> {
+    let mut track = vec![];
+    for (i, p) in points.iter().enumerate() {
+        let folded = i > 0 && points[i - 1].wait > 0 && starts_with_notes_off(Some(p));
+        track.extend(p.events[folded as usize..].iter().cloned());
+        encode_wait(&mut track, p.wait, starts_with_notes_off(points.get(i + 1)));
+    }
+    track
+}
+
+// State which is only read while the track is sounding, and can thus be dropped or moved while it is silent
+// Everything else either affects the channel, or whether releases are sounding.
+fn state_slot(e: &TrackEvent) -> Option<usize> {
+    Some(match e {
+        TrackEvent::SetVelocity(_) => 0,
+        TrackEvent::SetPan(_) => 1,
+        TrackEvent::SetVolume(_) => 2,
+        TrackEvent::SetA(_) => 3,
+        TrackEvent::SetD(_) => 4,
+        TrackEvent::SetS(_) => 5,
+        TrackEvent::SetPitchEnv(_) => 6,
+        TrackEvent::SetArpeggio(_) => 7,
+        TrackEvent::SetPortamento(_) => 8,
+        TrackEvent::SetGlide(_) => 9,
+        TrackEvent::SetVibrato(_) => 10,
+        TrackEvent::SetTremolo(_) => 11,
+        TrackEvent::SetDutySequence(_) => 12,
+        _ => return None,
+    })
+}
+const STATE_SLOTS: usize = 13;
+const PAN_SLOT: usize = 1;
+const ARP_SLOT: usize = 7;
+
+fn flags_pan(flags: u8) -> Option<TrackEvent> {
+    match (flags >> 4) & 0x3 {
+        0 => Some(TrackEvent::SetPan(Pan::Stereo)),
+        1 => Some(TrackEvent::SetPan(Pan::Left)),
+        2 => Some(TrackEvent::SetPan(Pan::Right)),
+        _ => None,
+    }
+}
+
+// Mirrors enough of the runtime to know if a track is sounding, starting from the same state as `w4on2_rt_init`
+#[derive(Default)]
+struct Sim {
+    held: bool,
+    released: bool,
+    release_ticks: usize,
+    r: u8,
+    flags: u8,
+    macros: [bool; W4ON2_MACRO_KIND_COUNT as usize],
+}
+impl Sim {
+    fn feed(&mut self, e: &TrackEvent) {
+        match e {
+            TrackEvent::NoteOn(_) => {
+                self.held = true;
+                self.released = false;
+            }
+            TrackEvent::NotesOff if self.held => {
+                self.held = false;
+                self.released = true;
+                self.release_ticks = 0;
+            }
+            TrackEvent::SetFlags(f) => self.flags = *f,
+            TrackEvent::SetADSR(ADSR(_, _, _, r)) | TrackEvent::SetR(r) => self.r = *r,
+            TrackEvent::SetMacro(kind, m) => self.macros[*kind as usize] = m.is_some(),
+            _ => {}
+        }
+    }
+    // if any tick of the following wait reads the track state
+    fn sounding(&self) -> bool {
+        if self.held {
+            true
+        } else if !self.released {
+            false
+        } else if self.flags & W4ON2_FLAG_RELEASE_EFFECTS as u8 != 0 || self.macros.contains(&true) {
+            self.release_ticks < self.r as usize
+        } else {
+            self.release_ticks == 0
+        }
+    }
+    fn wait(&mut self, ticks: usize) {
+        self.release_ticks += ticks;
+    }
+}
+
+// Use single field SetA/SetD/SetS/SetR when at most two fields changed
+fn split_adsr(points: &mut [Point]) {
+    let mut cur: [Option<u8>; 4] = [None; 4];
+    for p in points {
+        let mut events = Vec::with_capacity(p.events.len());
+        for e in p.events.drain(..) {
+            match e {
+                TrackEvent::SetADSR(ADSR(a, d, s, r)) => {
+                    let new = [a, d, s, r];
+                    let changed: Vec<usize> = (0..4).filter(|i| cur[*i] != Some(new[*i])).collect();
+                    if changed.len() <= 2 {
+                        events.extend(changed.into_iter().map(|i| match i {
+                            0 => TrackEvent::SetA(a),
+                            1 => TrackEvent::SetD(d),
+                            2 => TrackEvent::SetS(s),
+                            _ => TrackEvent::SetR(r),
+                        }));
+                    } else {
+                        events.push(TrackEvent::SetADSR(ADSR(a, d, s, r)));
+                    }
+                    cur = new.map(Some);
+                }
+                e => {
+                    match e {
+                        TrackEvent::SetA(a) => cur[0] = Some(a),
+                        TrackEvent::SetD(d) => cur[1] = Some(d),
+                        TrackEvent::SetS(s) => cur[2] = Some(s),
+                        TrackEvent::SetR(r) => cur[3] = Some(r),
+                        _ => {}
+                    }
+                    events.push(e);
+                }
+            }
+        }
+        p.events = events;
+    }
+}
+
+// Drop state changes which set what is already set, or are overridden before being read
+// Unless `releases_read`, state is only counted as read while a note is held.
+fn drop_ineffective(points: &mut [Point], releases_read: bool) {
+    let mut sim = Sim::default();
+    let mut cur: [Option<TrackEvent>; STATE_SLOTS] = Default::default();
+    let mut committed: [Option<TrackEvent>; STATE_SLOTS] = Default::default(); // last value that was read
+    let mut pending: [Option<(usize, usize)>; STATE_SLOTS] = [None; STATE_SLOTS]; // point and event index
+    let mut keep: Vec<Vec<bool>> = points.iter().map(|p| vec![true; p.events.len()]).collect();
+    // overwrite a slot with an event that is always kept
+    let overwrite = |slot: usize,
+                     value: Option<TrackEvent>,
+                     cur: &mut [Option<TrackEvent>],
+                     committed: &mut [Option<TrackEvent>],
+                     pending: &mut [Option<(usize, usize)>],
+                     keep: &mut [Vec<bool>]| {
+        if let Some((pi, ei)) = pending[slot].take() {
+            keep[pi][ei] = false;
+        }
+        cur[slot] = value.clone();
+        committed[slot] = value;
+    };
+    for (pi, p) in points.iter().enumerate() {
+        for (ei, e) in p.events.iter().enumerate() {
+            if let Some(slot) = state_slot(e) {
+                if cur[slot].as_ref() != Some(e) {
+                    if let Some((ppi, pei)) = pending[slot].take() {
+                        keep[ppi][pei] = false;
+                        cur[slot] = committed[slot].clone();
+                    }
+                }
+                if cur[slot].as_ref() == Some(e) {
+                    keep[pi][ei] = false;
+                } else {
+                    pending[slot] = Some((pi, ei));
+                    cur[slot] = Some(e.clone());
+                }
+                continue;
+            }
+            match e {
+                TrackEvent::SetFlags(f) => {
+                    overwrite(
+                        PAN_SLOT,
+                        flags_pan(*f),
+                        &mut cur,
+                        &mut committed,
+                        &mut pending,
+                        &mut keep,
+                    );
+                }
+                TrackEvent::SetADSR(ADSR(a, d, s, _)) => {
+                    for (slot, value) in [
+                        (3, TrackEvent::SetA(*a)),
+                        (4, TrackEvent::SetD(*d)),
+                        (5, TrackEvent::SetS(*s)),
+                    ] {
+                        overwrite(slot, Some(value), &mut cur, &mut committed, &mut pending, &mut keep);
+                    }
+                }
+                TrackEvent::NotesOff => {
+                    // reads the arpeggio rate to know which key was released
+                    pending[ARP_SLOT] = None;
+                    committed[ARP_SLOT] = cur[ARP_SLOT].clone();
+                }
+                _ => {}
+            }
+            sim.feed(e);
+        }
+        if p.wait > 0 {
+            if sim.held || releases_read && sim.sounding() {
+                pending = [None; STATE_SLOTS];
+                committed = cur.clone();
+            }
+            sim.wait(p.wait);
+        }
+    }
+    for (p, keep) in points.iter_mut().zip(keep) {
+        let mut keep = keep.into_iter();
+        p.events.retain(|_| keep.next().unwrap());
+    }
+}
+
+// Move points with only state changes to before or after a silent wait, when merging waits saves bytes
+// Points left empty by dropped events are merged too.
+fn merge_waits(points: Vec<Point>) -> Vec<Point> {
+    let mut sim = Sim::default();
+    let sounding: Vec<bool> = points
+        .iter()
+        .map(|p| {
+            for e in &p.events {
+                sim.feed(e);
+            }
+            let sounding = p.wait > 0 && sim.sounding();
+            sim.wait(p.wait);
+            sounding
+        })
+        .collect();
+    let mut merged: Vec<(Point, bool)> = Vec::with_capacity(points.len());
+    let mut rest = points.into_iter().zip(sounding).peekable();
+    while let Some((mut p, sounding)) = rest.next() {
+        if let Some((prev, prev_sounding)) = merged.last_mut() {
+            let moved = if p.events.is_empty() {
+                true
+            } else if let Some((next, _)) = rest.peek_mut() {
+                let (a, b) = (prev.wait, p.wait);
+                let notes_off = starts_with_notes_off(Some(next));
+                let has_arp = p.events.iter().any(|e| state_slot(e) == Some(ARP_SLOT));
+                if a == 0
+                    || b == 0
+                    || !p.events.iter().all(|e| state_slot(e).is_some())
+                    || wait_size(a + b, notes_off) >= wait_size(a, false) + wait_size(b, notes_off)
+                {
+                    false
+                } else if !(sounding || notes_off && has_arp) {
+                    // later, after a NotesOff so it still folds into the wait
+                    let at = notes_off as usize;
+                    next.events.splice(at..at, p.events.drain(..));
+                    true
+                } else if !*prev_sounding {
+                    // earlier
+                    prev.events.append(&mut p.events);
+                    true
+                } else {
+                    false
+                }
+            } else {
+                false
+            };
+            if moved {
+                prev.wait += p.wait;
+                *prev_sounding |= sounding;
+                continue;
+            }
+        }
+        merged.push((p, sounding));
+    }
+    merged.into_iter().map(|(p, _)| p).collect()
+}
+
+pub fn optimize_track(track: &[TrackEvent]) -> Vec<TrackEvent> {
+    let mut points = to_points(track);
+    split_adsr(&mut points);
+    drop_ineffective(&mut points, true);
+    let points = merge_waits(points);
+    from_points(&points)
+}
+
+// Also drops state changes which are only read by releases, which changes how they sound
+pub(crate) fn drop_redundant_sets(track: &[TrackEvent]) -> Vec<TrackEvent> {
+    let mut points = to_points(track);
+    drop_ineffective(&mut points, false);
+    from_points(&points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TrackEvent::*;
+
+    fn size(track: &[TrackEvent]) -> usize {
+        let mut buf = vec![];
+        for e in track {
+            e.serialize_into(&mut buf);
+        }
+        buf.len()
+    }
+
+    #[test]
+    fn test_waits() {
+        assert_eq!(
+            optimize_track(&[NoteOn(1), Delta(60)]),
+            vec![NoteOn(1), Delta(10), Delta(50)]
+        );
+        assert_eq!(
+            optimize_track(&[NoteOn(1), Delta(60), NotesOff]),
+            vec![NoteOn(1), Delta(10), DeltaNotesOff(50)]
+        );
+        assert_eq!(
+            optimize_track(&[NoteOn(1), DeltaNotesOff(200)]),
+            vec![NoteOn(1), DeltaNotesOff(200)]
+        );
+        assert_eq!(
+            optimize_track(&[NoteOn(1), Delta(4), Delta(5)]),
+            vec![NoteOn(1), Delta(9)]
+        );
+    }
+
+    #[test]
+    fn test_adsr() {
+        let track = [
+            SetADSR(ADSR(1, 2, 3, 4)),
+            NoteOn(1),
+            DeltaNotesOff(4),
+            SetADSR(ADSR(1, 5, 3, 4)),
+            NoteOn(1),
+            DeltaNotesOff(4),
+            SetADSR(ADSR(6, 7, 8, 4)),
+            NoteOn(1),
+            DeltaNotesOff(4),
+        ];
+        let optimized = optimize_track(&track);
+        assert!(optimized.contains(&SetD(5)));
+        assert!(optimized.contains(&SetADSR(ADSR(6, 7, 8, 4))));
+        assert!(size(&optimized) < size(&track));
+    }
+
+    #[test]
+    fn test_ineffective() {
+        // velocity changed while silent, pan overwritten by flags, and velocity set to what it was
+        let track = [
+            SetFlags(0),
+            SetADSR(ADSR(0, 0, 0, 0)),
+            SetVelocity(10),
+            NoteOn(1),
+            DeltaNotesOff(4),
+            Delta(10),
+            SetVelocity(20),
+            SetPan(Pan::Left),
+            Delta(10),
+            SetVelocity(10),
+            SetFlags(0),
+            NoteOn(1),
+            DeltaNotesOff(4),
+        ];
+        assert_eq!(
+            optimize_track(&track),
+            vec![
+                SetFlags(0),
+                SetADSR(ADSR(0, 0, 0, 0)),
+                SetVelocity(10),
+                NoteOn(1),
+                DeltaNotesOff(4),
+                Delta(20),
+                SetFlags(0),
+                NoteOn(1),
+                DeltaNotesOff(4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_release_is_sounding() {
+        // per tick release reads the velocity until it's done
+        let track = [
+            SetFlags(W4ON2_FLAG_RELEASE_EFFECTS as u8),
+            SetADSR(ADSR(0, 0, 0, 8)),
+            NoteOn(1),
+            DeltaNotesOff(4),
+            Delta(2),
+            SetVelocity(20),
+            Delta(10),
+            NoteOn(1),
+        ];
+        assert_eq!(optimize_track(&track), track);
+    }
+
+    #[test]
+    fn test_merge_waits() {
+        // a tremolo change while silent is moved to the next note
+        let track = [
+            NoteOn(1),
+            DeltaNotesOff(4),
+            Delta(10),
+            SetTremolo(crate::Tremolo { speed: 1, depth: 2 }),
+            Delta(10),
+            NoteOn(1),
+        ];
+        let optimized = optimize_track(&track);
+        assert_eq!(
+            optimized[2..],
+            [Delta(20), SetTremolo(crate::Tremolo { speed: 1, depth: 2 }), NoteOn(1)]
+        );
+    }
+
+    #[test]
+    fn test_drop_redundant_sets() {
+        // pan and single ADSR fields only change part of what came before
+        let track = vec![
+            SetFlags(1),
+            SetADSR(ADSR(1, 2, 3, 4)),
+            SetPan(Pan::Left),
+            SetA(5),
+            NoteOn(60),
+            DeltaNotesOff(4),
+        ];
+        assert_eq!(drop_redundant_sets(&track), track);
+        // while the whole flags and ADSR do replace them, and setting the same again does nothing
+        let track = vec![
+            SetPan(Pan::Left),
+            SetA(5),
+            SetVelocity(10),
+            SetFlags(1),
+            SetADSR(ADSR(1, 2, 3, 4)),
+            NoteOn(60),
+            DeltaNotesOff(4),
+            SetVelocity(10),
+            NoteOn(60),
+        ];
+        assert_eq!(
+            drop_redundant_sets(&track),
+            [
+                SetVelocity(10),
+                SetFlags(1),
+                SetADSR(ADSR(1, 2, 3, 4)),
+                NoteOn(60),
+                DeltaNotesOff(4),
+                NoteOn(60),
+            ]
+        );
+        // a velocity change during a release
+        let track = [
+            SetFlags(W4ON2_FLAG_RELEASE_EFFECTS as u8),
+            SetADSR(ADSR(0, 0, 0, 8)),
+            NoteOn(1),
+            DeltaNotesOff(4),
+            SetVelocity(20),
+            Delta(2),
+            SetVelocity(30),
+            NoteOn(1),
+        ];
+        assert_eq!(optimize_track(&track), track);
+        assert!(!drop_redundant_sets(&track).contains(&SetVelocity(20)));
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use log::*;
+use midly::{MetaMessage, MidiMessage, Smf, TrackEventKind};
+
+use crate::{convert::ConvertError, *};
+
+// Highest key of parts which get the triangle channel when they only play one note at a time
+const LOW_RANGE_MAX_KEY: u8 = 60; // C4
+const DRUM_CHANNEL: u8 = 9; // General MIDI channel 10
+
+// Notes of one MIDI channel within one MIDI track
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiPart {
+    pub midi_track: usize,
+    pub name: Option<String>, // of the MIDI track
+    pub channel: u8,
+    pub notes: usize,
+    pub lowest: u8,
+    pub highest: u8,
+    pub polyphony: usize, // most notes held at once
+}
+impl MidiPart {
+    fn new(midi_track: usize, name: Option<String>, channel: u8) -> Self {
+        Self {
+            midi_track,
+            name,
+            channel,
+            notes: 0,
+            lowest: u8::MAX,
+            highest: 0,
+            polyphony: 0,
+        }
+    }
+}
+
+// Finds which MIDI channels each track plays notes on
+pub fn scan_midi(midi_bytes: &[u8]) -> Result<Vec<MidiPart>, ConvertError> {
+    let smf = Smf::parse(midi_bytes).map_err(ConvertError::InvalidMidi)?;
+    let mut parts = vec![];
+    for (midi_track_i, midi_events) in smf.tracks.iter().enumerate() {
+        let name = midi_events.iter().find_map(|e| match e.kind {
+            TrackEventKind::Meta(MetaMessage::TrackName(name)) => Some(String::from_utf8_lossy(name).trim().to_owned()),
+            _ => None,
+        });
+        let name = name.filter(|n| !n.is_empty());
+        let mut track_parts = BTreeMap::<u8, MidiPart>::new();
+        let mut held: [BTreeSet<u8>; 16] = Default::default();
+        for event in midi_events {
+            let TrackEventKind::Midi { channel, message } = event.kind else {
+                continue;
+            };
+            let ch = channel.as_int();
+            match message {
+                MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                    let part = track_parts
+                        .entry(ch)
+                        .or_insert_with(|| MidiPart::new(midi_track_i, name.clone(), ch));
+                    part.notes += 1;
+                    part.lowest = part.lowest.min(key.as_int());
+                    part.highest = part.highest.max(key.as_int());
+                    held[ch as usize].insert(key.as_int());
+                    part.polyphony = part.polyphony.max(held[ch as usize].len());
+                }
+                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                    held[ch as usize].remove(&key.as_int());
+                }
+                _ => {}
+            }
+        }
+        parts.extend(track_parts.into_values());
+    }
+    Ok(parts)
+}
+
+// Picks an instrument for every part, giving parts which share a MIDI channel with differently named tracks
+// their own channel entries matched by track name.
+pub fn starter_config(parts: &[MidiPart]) -> SongConfig {
+    let names_on = |ch: u8| -> BTreeSet<&str> {
+        parts
+            .iter()
+            .filter(|p| p.channel == ch)
+            .filter_map(|p| p.name.as_deref())
+            .collect()
+    };
+
+    // Merge parts which end up on the same channel entry, in the order they first appear
+    // Tracks matched by name can't share an entry, even with the same name, so each gets its own.
+    let mut voices: Vec<((u8, Option<&str>, usize), MidiPart)> = vec![];
+    for part in parts {
+        let by_name = part.name.as_deref().filter(|_| names_on(part.channel).len() > 1);
+        let key = (part.channel, by_name, by_name.map_or(0, |_| part.midi_track));
+        if let Some((_, v)) = voices.iter_mut().find(|(k, _)| *k == key) {
+            v.notes += part.notes;
+            v.lowest = v.lowest.min(part.lowest);
+            v.highest = v.highest.max(part.highest);
+            v.polyphony = v.polyphony.max(part.polyphony);
+        } else {
+            voices.push((key, part.clone()));
+        }
+    }
+    // tracks without a name can only be matched by MIDI channel, so they go first
+    voices.sort_by_key(|((_, by_name, _), _)| by_name.is_some());
+
+    let mut conf = SongConfig::default();
+    let mut taken = [false; 16]; // by MIDI channels, so other voices are only put on unused ones
+    for ((ch, _, _), _) in &voices {
+        taken[*ch as usize] = true;
+    }
+    let mut assigned = [false; 16];
+    let mut pulse_i = 0;
+    for ((ch, by_name, _), voice) in &voices {
+        let slot = if !assigned[*ch as usize] {
+            *ch as usize
+        } else if let Some(free) = (0..16).find(|i| !taken[*i]) {
+            taken[free] = true;
+            free
+        } else {
+            warn!("No free channel for MIDI track {}, skipping it", voice.midi_track);
+            continue;
+        };
+        assigned[slot] = true;
+
+        let track = &mut conf.channels[slot];
+        track.nickname = voice.name.clone().unwrap_or_default();
+        track.midi_track = by_name.map(str::to_owned);
+        if *ch == DRUM_CHANNEL {
+            track.channel = Channel::Noise;
+            track.adsr = ADSR(0, 10, 0, 0);
+        } else if voice.polyphony <= 1 && voice.highest <= LOW_RANGE_MAX_KEY {
+            track.channel = Channel::Triangle;
+        } else {
+            let duty = if voice.polyphony > 1 {
+                track.arpeggio.rate = 2;
+                PulseDuty::D50
+            } else {
+                PulseDuty::D25
+            };
+            track.channel = if pulse_i % 2 == 0 {
+                Channel::Pulse1(duty)
+            } else {
+                Channel::Pulse2(duty)
+            };
+            pulse_i += 1;
+        }
+    }
+    conf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(midi_track: usize, name: &str, channel: u8, lowest: u8, highest: u8, polyphony: usize) -> MidiPart {
+        MidiPart {
+            midi_track,
+            name: Some(name.to_owned()).filter(|n| !n.is_empty()),
+            channel,
+            notes: 1,
+            lowest,
+            highest,
+            polyphony,
+        }
+    }
+
+    #[test]
+    fn test_starter_config() {
+        let conf = starter_config(&[
+            part(1, "Lead", 0, 60, 80, 1),
+            part(2, "Chords", 1, 50, 70, 3),
+            part(3, "Bass", 2, 30, 50, 1),
+            part(4, "", 9, 36, 42, 2),
+        ]);
+        assert_eq!(conf.channels[0].channel, Channel::Pulse1(PulseDuty::D25));
+        assert_eq!(conf.channels[0].nickname, "Lead");
+        assert_eq!(conf.channels[1].channel, Channel::Pulse2(PulseDuty::D50));
+        assert_eq!(conf.channels[1].arpeggio.rate, 2);
+        assert_eq!(conf.channels[2].channel, Channel::Triangle);
+        assert_eq!(conf.channels[9].channel, Channel::Noise);
+        assert!(conf.channels.iter().all(|c| c.midi_track.is_none()));
+    }
+
+    #[test]
+    fn test_starter_config_by_name() {
+        // every track on the first MIDI channel
+        let conf = starter_config(&[
+            part(1, "Lead", 0, 60, 80, 1),
+            part(2, "Bass", 0, 30, 50, 1),
+            part(3, "Lead", 0, 70, 90, 1),
+        ]);
+        assert_eq!(conf.channels[0].midi_track.as_deref(), Some("Lead"));
+        assert_eq!(conf.channels[0].channel, Channel::Pulse1(PulseDuty::D25));
+        assert_eq!(conf.channels[1].midi_track.as_deref(), Some("Bass"));
+        assert_eq!(conf.channels[1].channel, Channel::Triangle);
+        assert_eq!(conf.channels[2].midi_track.as_deref(), Some("Lead"));
+        assert_eq!(conf.channels[2].channel, Channel::Pulse2(PulseDuty::D25));
+        assert!(conf.channels[3..].iter().all(|c| c.midi_track.is_none()));
+    }
+
+    #[test]
+    fn test_starter_config_converts() {
+        use midly::{Format, Header, Timing, TrackEvent};
+        let track = |name: &'static str, key: u8| {
+            let midi = |delta: u32, message| TrackEvent {
+                delta: delta.into(),
+                kind: TrackEventKind::Midi {
+                    channel: 0.into(),
+                    message,
+                },
+            };
+            vec![
+                TrackEvent {
+                    delta: 0.into(),
+                    kind: TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+                },
+                midi(
+                    0,
+                    MidiMessage::NoteOn {
+                        key: key.into(),
+                        vel: 100.into(),
+                    },
+                ),
+                midi(
+                    96,
+                    MidiMessage::NoteOff {
+                        key: key.into(),
+                        vel: 0.into(),
+                    },
+                ),
+            ]
+        };
+        let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(96.into())));
+        let meta = |kind| TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(kind),
+        };
+        smf.tracks.push(vec![
+            meta(MetaMessage::Tempo(500000.into())),
+            meta(MetaMessage::TimeSignature(4, 2, 24, 8)),
+        ]);
+        smf.tracks
+            .extend([track("Lead", 72), track("Bass", 40), track("Lead", 76)]);
+        let mut midi_bytes = vec![];
+        smf.write_std(&mut midi_bytes).unwrap();
+
+        let toml = starter_config(&scan_midi(&midi_bytes).unwrap()).to_toml().unwrap();
+        let conf = SongConfig::from_toml(&toml).unwrap();
+        let (_, report) = convert::convert(&conf, &midi_bytes, true, false).unwrap();
+        let channels: Vec<usize> = report.tracks.iter().map(|t| t.channel).collect();
+        assert_eq!(channels, [0, 1, 2]);
+    }
+}
@@ -5,6 +5,7 @@
 // -> configurable SAMPLE_RATE
 // -> further cleanup
 // -> updated to https://github.com/aduros/wasm4/blob/0dff7ad4e6c7b28b87a6555bea8574e5aa748e27/runtimes/native/src/apu.c
+// -> mixing in i32
 
 /*
 Copyright (c) Bruno Garcia
@@ -63,6 +64,7 @@ pub struct APU {
     ticks: u64,
     sample_rate: u32,
     channels: [Channel; 4],
+    pub taps: Option<[Vec<i16>; 4]>, // stereo output of each channel, appended to by `write_samples` if set
 }
 impl APU {
     pub fn new(sample_rate: u32) -> APU {
@@ -70,6 +72,7 @@ impl APU {
             time: 0,
             ticks: 0,
             sample_rate,
+            taps: None,
             channels: [
                 Channel::default(),
                 Channel::default(),
@@ -143,12 +146,19 @@ impl APU {
                 .wrapping_add((self.sample_rate as i32 / 1000_i32) as u64);
         }
     }
+    // Nothing is playing, including releases
+    pub fn is_silent(&self) -> bool {
+        self.channels
+            .iter()
+            .all(|channel| self.time >= channel.releaseTime && self.ticks != channel.endTick)
+    }
     pub fn write_samples(&mut self, output: &mut [i16], frames: usize) {
         for ii in 0..frames {
-            let mut mix_left: i16 = 0;
-            let mut mix_right: i16 = 0;
+            let mut mix_left: i32 = 0;
+            let mut mix_right: i32 = 0;
             for channelIdx in 0..4 {
                 let channel = &mut self.channels[channelIdx as usize];
+                let mut tap: [i16; 2] = [0; 2];
                 if self.time < channel.releaseTime || self.ticks == channel.endTick {
                     let freq = getCurrentFrequency(channel, self.time);
                     let volume = getCurrentVolume(channel, self.time, self.sample_rate);
@@ -198,15 +208,21 @@ impl APU {
                         }
                     }
                     if channel.pan as i32 != 1_i32 {
-                        mix_right = (mix_right as i32 + sample as i32) as i16;
+                        mix_right += sample as i32;
+                        tap[1] = sample;
                     }
                     if channel.pan as i32 != 2_i32 {
-                        mix_left = (mix_left as i32 + sample as i32) as i16;
+                        mix_left += sample as i32;
+                        tap[0] = sample;
                     }
                 }
+                if let Some(taps) = &mut self.taps {
+                    taps[channelIdx as usize].extend(tap);
+                }
             }
-            output[ii * 2] = mix_left;
-            output[ii * 2 + 1] = mix_right;
+            // every channel at full volume only adds up to 22937, so the mix can't overflow
+            output[ii * 2] = mix_left as i16;
+            output[ii * 2 + 1] = mix_right as i16;
             self.time += 1;
         }
     }